@@ -0,0 +1,141 @@
+use std::{fmt::Debug, net::IpAddr, sync::Arc, thread, time::Duration};
+
+use crossbeam::channel::Receiver;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::components::player::ProfileProperty;
+
+pub const MOJANG_SESSION_SERVER: &str = "https://sessionserver.mojang.com";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameProfile {
+    pub id: uuid::Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<ProfileProperty>,
+}
+
+/// Verifies that a player joined through the session server.
+/// Implement this to authenticate against something other than Mojang.
+pub trait SessionServer: Debug + Send + Sync {
+    /// Returns `None` if the session server doesn't know the player.
+    fn has_joined(&self, username: &str, server_hash: &str, ip: Option<IpAddr>) -> anyhow::Result<Option<GameProfile>>;
+}
+
+/// Session server speaking the Mojang `hasJoined` HTTP API.
+#[derive(Debug)]
+pub struct HttpSessionServer {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpSessionServer {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+        }
+    }
+}
+
+impl Default for HttpSessionServer {
+    fn default() -> Self {
+        Self::new(MOJANG_SESSION_SERVER)
+    }
+}
+
+impl SessionServer for HttpSessionServer {
+    fn has_joined(&self, username: &str, server_hash: &str, ip: Option<IpAddr>) -> anyhow::Result<Option<GameProfile>> {
+        let url = format!("{}/session/minecraft/hasJoined", self.base_url);
+        let mut request = self.agent.get(&url)
+            .query("username", username)
+            .query("serverId", server_hash);
+        if let Some(ip) = ip {
+            request = request.query("ip", &ip.to_string());
+        }
+
+        let response = request.call()?;
+        if response.status() == 204 {
+            return Ok(None);
+        }
+        Ok(Some(response.into_json()?))
+    }
+}
+
+pub type AuthResult = anyhow::Result<Option<GameProfile>>;
+
+/// Runs the `hasJoined` request off the tick thread.
+pub fn spawn_has_joined(
+    session: Arc<dyn SessionServer>,
+    username: String,
+    server_hash: String,
+    ip: Option<IpAddr>,
+) -> Receiver<AuthResult> {
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    let spawned = thread::Builder::new()
+        .name("authentication".to_string())
+        .spawn({
+            let tx = tx.clone();
+            move || {
+                let _ = tx.send(session.has_joined(&username, &server_hash, ip));
+            }
+        });
+    if let Err(err) = spawned {
+        let _ = tx.send(Err(err.into()));
+    }
+    rx
+}
+
+/// Minecraft's server hash: a sha1 of the server id, shared secret and public
+/// key, printed as a signed hex number.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id)
+        .chain_update(shared_secret)
+        .chain_update(public_key)
+        .finalize()
+        .into();
+
+    signed_hex(digest)
+}
+
+fn signed_hex(mut bytes: [u8; 20]) -> String {
+    let negative = bytes[0] & 0x80 != 0;
+    if negative {
+        // two's complement
+        let mut carry = true;
+        for byte in bytes.iter_mut().rev() {
+            *byte = !*byte;
+            if carry {
+                (*byte, carry) = byte.overflowing_add(1);
+            }
+        }
+    }
+
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    let hex = match hex.trim_start_matches('0') {
+        "" => "0",
+        hex => hex,
+    };
+
+    if negative {
+        format!("-{hex}")
+    } else {
+        hex.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::server_hash;
+
+    #[test]
+    fn server_hash_matches_known_vectors() {
+        assert_eq!(server_hash("Notch", &[], &[]), "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48");
+        assert_eq!(server_hash("jeb_", &[], &[]), "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1");
+        assert_eq!(server_hash("simon", &[], &[]), "88e16a1019277b15d58faf0541e11910eb756f6");
+    }
+}
@@ -1,10 +1,16 @@
-use std::{fmt::Display, net::{IpAddr, TcpStream}};
+use std::{fmt::Display, net::{IpAddr, TcpStream}, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Instant};
 
+use aes::cipher::{generic_array::GenericArray, BlockDecryptMut, BlockSizeUser, KeyIvInit};
 use bytes::BytesMut;
+use crossbeam::channel::Receiver;
 use derive_more::derive::{Deref, DerefMut};
 use flecs_ecs::prelude::*;
+use parking_lot::{Mutex, MutexGuard};
 use pumpkin_config::compression::CompressionInfo;
 use pumpkin_protocol::{ClientPacket, ConnectionState, PacketError, RawPacket};
+use valence_text::Text;
+
+use crate::{auth::AuthResult, components::{player::ProfileProperty, resources::KeyPair}, error::PacketIoError, modules::{LoginProfile, LoginQueries}};
 
 #[derive(Default, Component)]
 pub struct PacketEncoder(pumpkin_protocol::packet_encoder::PacketEncoder);
@@ -29,14 +35,113 @@ impl PacketEncoder {
 
 }
 
-#[derive(Default, Component, Deref, DerefMut)]
-pub struct PacketDecoder(pumpkin_protocol::packet_decoder::PacketDecoder);
+/// Shared with the IO thread, which decodes incoming data as soon as it arrives.
+#[derive(Default, Component, Clone)]
+pub struct PacketDecoder {
+    inner: Arc<Mutex<FrameDecoder>>,
+    /// Packets larger than this close the connection, 0 disables the check.
+    max_packet_size: Arc<AtomicUsize>,
+}
+
+impl PacketDecoder {
+    pub fn lock(&self) -> MutexGuard<'_, FrameDecoder> {
+        self.inner.lock()
+    }
+
+    /// A client sends nothing between its encryption response and the
+    /// server's answer, so everything queued from here on is encrypted.
+    pub fn set_encryption(&self, key: Option<&[u8; 16]>) {
+        self.inner.lock().cipher = key.map(|key| Cipher::new_from_slices(key, key).expect("invalid key"));
+    }
+
+    pub fn set_compression(&self, compression: bool) {
+        self.inner.lock().decoder.set_compression(compression);
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_packet_size(&self, max: usize) {
+        self.max_packet_size.store(max, Ordering::Relaxed);
+    }
+}
+
+type Cipher = cfb8::Decryptor<aes::Aes128>;
+
+/// Room for the packet id and the uncompressed length in front of a packet.
+const MAX_FRAME_OVERHEAD: usize = 10;
+
+/// Decrypts incoming data and follows the frame length prefixes, so an
+/// oversized frame is refused from its first bytes instead of once it's buffered.
+#[derive(Default)]
+pub struct FrameDecoder {
+    decoder: pumpkin_protocol::packet_decoder::PacketDecoder,
+    cipher: Option<Cipher>,
+    /// Bytes of the current frame that weren't queued yet.
+    frame_remaining: usize,
+    /// The length prefix of the next frame read so far.
+    prefix: u32,
+    prefix_bytes: u32,
+}
+
+impl FrameDecoder {
+    /// Queues data read from the socket, `max_packet_size` 0 disables the check.
+    pub fn queue_slice(&mut self, bytes: &[u8], max_packet_size: usize) -> Result<(), PacketIoError> {
+        let mut bytes = bytes.to_vec();
+        if let Some(cipher) = &mut self.cipher {
+            for chunk in bytes.chunks_mut(Cipher::block_size()) {
+                cipher.decrypt_block_mut(GenericArray::from_mut_slice(chunk));
+            }
+        }
+
+        let mut rest = bytes.as_slice();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if self.frame_remaining > 0 {
+                let skipped = self.frame_remaining.min(rest.len());
+                self.frame_remaining -= skipped;
+                rest = &rest[skipped..];
+                continue;
+            }
+            rest = tail;
+
+            self.prefix |= u32::from(byte & 0x7F) << (7 * self.prefix_bytes);
+            self.prefix_bytes += 1;
+            if byte & 0x80 != 0 {
+                // vanilla frames are at most 2^21 - 1 bytes long
+                if self.prefix_bytes == 3 {
+                    return Err(PacketIoError::BadPacket("frame length too long"));
+                }
+                continue;
+            }
+
+            let len = self.prefix as usize;
+            self.prefix = 0;
+            self.prefix_bytes = 0;
+            if max_packet_size != 0 && len > max_packet_size + MAX_FRAME_OVERHEAD {
+                return Err(PacketIoError::BadPacket("packet too large"));
+            }
+            self.frame_remaining = len;
+        }
+
+        self.decoder.queue_slice(&bytes);
+        Ok(())
+    }
+
+    pub fn decode(&mut self) -> Result<Option<RawPacket>, PacketError> {
+        self.decoder.decode()
+    }
+}
 
 #[derive(Component, Default, Deref, DerefMut, Clone)]
 pub struct ClientPacketQueue(Vec<RawPacket>);
 
 #[derive(Debug, Component, Deref, DerefMut)]
-pub struct SlabId(pub usize);
+pub struct ConnectionId(pub usize);
+
+/// Encoded bytes the socket didn't accept yet, drained across ticks.
+#[derive(Debug, Default, Component, Deref, DerefMut)]
+pub struct OutgoingBuffer(pub BytesMut);
 
 #[derive(Debug, Component, Deref, DerefMut)]
 pub struct ClientConnection(pub TcpStream);
@@ -44,18 +149,73 @@ pub struct ClientConnection(pub TcpStream);
 #[derive(Debug, Component)]
 pub struct RemoteAddress(pub IpAddr);
 
+/// Marks a connection from one of `network.trusted_proxies`.
+#[derive(Debug, Component)]
+pub struct TrustedProxy;
+
+/// Lowercase hostname the client connected with, picks its virtual host.
+#[derive(Debug, Component)]
+pub struct Hostname(pub String);
+
+/// Marks a connection that opened with a pre-netty server list ping.
+#[derive(Debug, Component, Clone, Copy)]
+pub struct LegacyPing {
+    /// Sent by 1.4 and later, which expect the protocol and version in the response.
+    pub with_version: bool,
+}
+
+/// Identity forwarded by a BungeeCord proxy in the handshake, consumed at login start.
+#[derive(Debug, Clone, Component)]
+pub struct ForwardedProfile {
+    /// The player's address, the connection comes from the proxy.
+    pub address: IpAddr,
+    pub uuid: uuid::Uuid,
+    pub properties: Vec<ProfileProperty>,
+}
+
+/// Result of an online-mode `hasJoined` request running in the background.
+#[derive(Component)]
+pub struct PendingAuth(pub Receiver<AuthResult>);
+
+/// Kicks the client: the disconnect packet for its state is queued with the
+/// next flush and the connection is closed once everything queued before it
+/// was written, or after a short grace period.
+#[derive(Debug, Component)]
+pub struct Disconnect {
+    pub reason: Text,
+    /// When the disconnect packet was queued.
+    pub(crate) sent: Option<Instant>,
+}
+
+impl Disconnect {
+    pub fn new(reason: impl Into<Text>) -> Self {
+        Self {
+            reason: reason.into(),
+            sent: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum LoginState {
     LoginStart,
     EncryptionResponse {
         verify_token: [u8; 4],
-        uuid: uuid::Uuid,
+        username: String,
+        /// The key sent in the request, kept in case it's rotated meanwhile.
+        key_pair: KeyPair,
+    },
+    /// Waiting for the session server, see [`PendingAuth`].
+    Authenticating {
         username: String,
     },
     LoginAck,
-    VelocityResponse {
-        message_id: i32,
+    /// Waiting for login plugin responses before `CLoginSuccess`. The profile
+    /// is `None` while a proxy's forwarding query is outstanding.
+    Queries {
         username: String,
+        profile: Option<LoginProfile>,
+        queries: LoginQueries,
     },
 }
 
@@ -1,4 +1,6 @@
 use flecs_ecs::prelude::*;
+use pumpkin_protocol::Property;
+use serde::Deserialize;
 
 #[derive(Component)]
 pub struct Play;
@@ -9,6 +11,46 @@ pub struct Username(pub String);
 #[derive(Component)]
 pub struct Uuid(pub uuid::Uuid);
 
+/// Dimension the player spawns in, from its virtual host.
+#[derive(Component)]
+pub struct SpawnWorld(pub String);
+
+/// The client disabled "Allow Server Listings", keeps it out of the status sample.
+#[derive(Component)]
+pub struct HiddenFromStatus;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    pub signature: Option<String>,
+}
+
+impl From<ProfileProperty> for Property {
+    fn from(value: ProfileProperty) -> Self {
+        Property {
+            name: value.name,
+            value: value.value,
+            signature: value.signature,
+        }
+    }
+}
+
+/// Chat signing key forwarded by Velocity.
+#[derive(Debug, Clone, Component)]
+pub struct PlayerPublicKey {
+    /// Milliseconds since the unix epoch.
+    pub expires_at: i64,
+    pub key: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// Profile the key was issued to, if it differs from the player's.
+    pub holder: Option<uuid::Uuid>,
+}
+
+/// Game profile properties such as skin textures.
+#[derive(Debug, Clone, Default, Component)]
+pub struct ProfileProperties(pub Vec<ProfileProperty>);
+
 #[derive(Component)]
 pub struct ClientBrand(pub String);
 
@@ -1,44 +1,150 @@
-use std::{net::TcpListener, sync::{atomic::AtomicBool, Arc}};
+use std::{collections::HashMap, fmt::Debug, sync::{atomic::AtomicBool, Arc}};
 
+use crossbeam::channel::Receiver;
 use derive_more::derive::Deref;
 use flecs_ecs::prelude::*;
-use rsa::{pkcs8::Document, RsaPrivateKey};
-use sharded_slab::Slab;
+use parking_lot::Mutex;
+use rsa::{pkcs8::{Document, EncodePublicKey}, RsaPrivateKey};
+use serde::{Deserialize, Serialize};
+use valence_text::Text;
+
+use crate::{auth::SessionServer, net::{IoHandle, NetEvent, NetworkMetrics}};
 
 
 #[derive(Component)]
-pub struct ServerListener {
-    pub listener: TcpListener,
-    pub clients: Slab<Entity>,
+pub struct NetworkIo {
+    pub events: Receiver<NetEvent>,
+    pub handle: IoHandle,
+    pub metrics: Arc<NetworkMetrics>,
+    /// IO thread connection ids to their entities.
+    pub clients: HashMap<usize, Entity>,
+    /// Entities whose packet queue was filled last tick.
+    pub received: Vec<Entity>,
 }
 
-#[derive(Component)]
-pub struct KeyPair {
+/// The server's RSA key. Cheap to clone, logins waiting for an encryption
+/// response hold on to the one they were sent.
+#[derive(Component, Clone, Deref)]
+pub struct KeyPair(Arc<RsaKeys>);
+
+/// Keeps the private key out of logs.
+impl Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair").finish_non_exhaustive()
+    }
+}
+
+pub struct RsaKeys {
     pub private: RsaPrivateKey,
     pub public_bytes: Document,
 }
 
-#[derive(Component)]
+impl KeyPair {
+    pub fn new(private: RsaPrivateKey) -> anyhow::Result<Self> {
+        let public_bytes = private.to_public_key().to_public_key_der()?;
+        Ok(Self(Arc::new(RsaKeys { private, public_bytes })))
+    }
+}
+
+/// Kept up to date by observers on `ClientConnection` and `Play`.
+#[derive(Component, Default)]
 pub struct ServerStorage {
     pub connections: usize,
     pub online_players: usize,
+    /// Online players shown in the server list, without the ones that opted out.
+    pub sample: HashMap<uuid::Uuid, String>,
+    /// Entities of every logged in player, claimed by `finish_login` before
+    /// `CLoginSuccess` so logins within the same tick see each other.
+    pub players: Mutex<HashMap<uuid::Uuid, Entity>>,
 }
 
-#[derive(Debug, Default, Clone)]
-pub enum ConnectionMode {
+/// What happens when a player logs in while already connected.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateLogin {
+    /// Kick the existing session, like vanilla.
     #[default]
-    Offline,
+    KickExisting,
+    /// Keep the existing session and refuse the new login.
+    RefuseNew,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnectionMode {
+    Offline {
+        /// Log in without the RSA and AES handshake, offline mode gains nothing from it.
+        skip_encryption: bool,
+    },
     Velocity {
         secret: Arc<str>,
     },
+    /// Legacy BungeeCord IP forwarding, with BungeeGuard if `guard_tokens`
+    /// isn't empty.
+    BungeeCord {
+        guard_tokens: Arc<[String]>,
+    },
+    /// Players are verified against a session server.
+    Online {
+        session: Arc<dyn SessionServer>,
+        /// Send the player's IP to the session server so it can reject proxies.
+        prevent_proxy_connections: bool,
+    },
+}
+
+impl Default for ConnectionMode {
+    fn default() -> Self {
+        Self::Offline { skip_encryption: false }
+    }
 }
 
 #[derive(Component)]
 pub struct ServerConfig {
     pub max_players: usize,
-    pub description: String,
+    /// Used for hostnames without a virtual host.
+    pub default_host: VirtualHost,
+    /// Lowercase hostnames to their virtual host.
+    pub virtual_hosts: HashMap<String, Arc<VirtualHost>>,
+    /// Refuse hostnames missing from `virtual_hosts` instead of using `default_host`.
+    pub reject_unknown_hosts: bool,
+    pub compression_threshold: i32,
+    /// zlib level, 0 to 9.
+    pub compression_level: u32,
+    /// Compress connections from trusted proxies, which are usually local.
+    pub compress_trusted_proxies: bool,
+    /// Usernames or UUIDs that may join when the server is full.
+    pub player_limit_bypass: Vec<String>,
+    pub duplicate_login: DuplicateLogin,
+    /// Derive offline UUIDs from the bare username like older versions of this server.
+    pub legacy_offline_uuids: bool,
+}
+
+/// Status and login behaviour picked by the hostname a client connected with.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    pub description: Text,
+    /// Data URL of the png, sent as is in status responses.
     pub favicon: String,
     pub connection_mode: ConnectionMode,
+    /// Dimension players spawn in.
+    pub world: String,
+}
+
+impl ServerConfig {
+    /// The virtual host for a lowercase `hostname`, `None` if it's unknown and
+    /// unknown hosts are rejected.
+    pub fn host(&self, hostname: &str) -> Option<&VirtualHost> {
+        match self.virtual_hosts.get(hostname) {
+            Some(host) => Some(host),
+            None if self.reject_unknown_hosts => None,
+            None => Some(&self.default_host),
+        }
+    }
+
+    pub fn bypasses_player_limit(&self, uuid: &uuid::Uuid, username: &str) -> bool {
+        self.player_limit_bypass.iter().any(|entry| {
+            entry.eq_ignore_ascii_case(username) || entry.parse::<uuid::Uuid>().is_ok_and(|id| id == *uuid)
+        })
+    }
 }
 
 #[derive(Component, Clone, Deref)]
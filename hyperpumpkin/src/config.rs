@@ -0,0 +1,708 @@
+use std::{collections::HashMap, fs, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose, Engine};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+use valence_text::Text;
+
+use crate::{auth::{HttpSessionServer, MOJANG_SESSION_SERVER}, components::resources::{ConnectionMode, DuplicateLogin, ServerConfig, VirtualHost}, error::ConfigError, modules::{AccessSettings, KeepAliveSettings, KeySettings, NetworkSettings, PhaseTimeouts, QuerySettings, RconSettings, ShutdownSettings}};
+
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+pub const DEFAULT_WORLD: &str = "minecraft:overworld";
+pub const DEFAULT_KEY_PATH: &str = "server-key.pem";
+pub const DEFAULT_WHITELIST_PATH: &str = "whitelist.json";
+pub const DEFAULT_BANNED_PLAYERS_PATH: &str = "banned-players.json";
+pub const DEFAULT_BANNED_IPS_PATH: &str = "banned-ips.json";
+
+/// Environment variables with this prefix override keys of the config file.
+/// Nested keys are separated by a double underscore, e.g.
+/// `HYPERPUMPKIN_NETWORK__ADDRESS=0.0.0.0:25566`.
+const ENV_PREFIX: &str = "HYPERPUMPKIN_";
+const ENV_CONFIG_PATH: &str = "HYPERPUMPKIN_CONFIG";
+/// Tag of internally tagged enums like [`ConnectionSettings`].
+const ENUM_TAG: &str = "mode";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub server: ServerSection,
+    pub network: NetworkSection,
+    pub connection: ConnectionSettings,
+    pub keepalive: KeepAliveSection,
+    pub timeouts: TimeoutSection,
+    pub virtual_hosts: VirtualHostsSection,
+    pub query: QuerySection,
+    pub rcon: RconSection,
+    pub access: AccessSection,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSection {
+    pub max_players: usize,
+    /// Usernames or UUIDs that may join when the server is full.
+    pub player_limit_bypass: Vec<String>,
+    /// `kick-existing` or `refuse-new`, for players logging in twice.
+    pub duplicate_login: DuplicateLogin,
+    /// Derive offline UUIDs from the MD5 of the bare username, like versions
+    /// before the vanilla algorithm. Keeps existing player data matching.
+    pub legacy_offline_uuids: bool,
+    /// Plain text with `§` formatting codes, or a JSON text component if it
+    /// starts with `{` or `[`. Use `\n` for a second line.
+    pub description: String,
+    /// Path to a 64x64 png favicon, the built-in icon is used when empty.
+    pub favicon: String,
+    /// Dimension players spawn in.
+    pub world: String,
+    pub threads: usize,
+    pub tps: f32,
+    /// Disconnect reason sent to every client on shutdown.
+    pub shutdown_message: String,
+    /// Seconds to wait for clients to disconnect before quitting.
+    pub shutdown_timeout: u64,
+    /// PEM file with the server's RSA key, created on first start.
+    pub key_file: PathBuf,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            max_players: 10,
+            player_limit_bypass: Vec::new(),
+            duplicate_login: DuplicateLogin::default(),
+            legacy_offline_uuids: false,
+            description: "Hello, World!".to_string(),
+            favicon: String::new(),
+            world: DEFAULT_WORLD.to_string(),
+            threads: 4,
+            tps: 20.0,
+            shutdown_message: "Server closed".to_string(),
+            shutdown_timeout: 5,
+            key_file: PathBuf::from(DEFAULT_KEY_PATH),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSection {
+    pub address: SocketAddr,
+    /// Packets this size or larger are compressed, -1 disables compression.
+    pub compression_threshold: i32,
+    /// zlib level from 0 (fastest) to 9 (smallest).
+    pub compression_level: u32,
+    /// Set to false to skip compression for connections from `trusted_proxies`,
+    /// which only costs CPU on a local link.
+    pub compress_trusted_proxies: bool,
+    /// Unsent bytes a client may have queued before it is disconnected.
+    pub max_pending_bytes: usize,
+    /// Expect a HAProxy PROXY protocol header from `trusted_proxies`.
+    pub proxy_protocol: bool,
+    /// CIDRs such as `10.0.0.0/8`. Trusted proxies skip the per address limits.
+    pub trusted_proxies: Vec<IpNet>,
+    /// New connections an address may open per `throttle_window` seconds, 0 disables.
+    pub throttle_connections: u32,
+    pub throttle_window: u64,
+    /// Open connections per address, 0 disables.
+    pub max_connections_per_ip: usize,
+    /// Connections that haven't finished logging in, 0 disables.
+    pub max_pre_login: usize,
+    /// Bytes read per connection and tick, the rest waits in the socket. 0 disables.
+    pub max_bytes_per_tick: usize,
+    /// Clients sending more packets per tick are disconnected, 0 disables.
+    pub max_packets_per_tick: usize,
+    /// Largest decoded packet during handshake, status and login, 0 disables.
+    pub max_packet_size_login: usize,
+    /// Largest decoded packet from configuration on, 0 disables.
+    pub max_packet_size: usize,
+}
+
+impl Default for NetworkSection {
+    fn default() -> Self {
+        Self {
+            address: ([127, 0, 0, 1], 25565).into(),
+            compression_threshold: 256,
+            compression_level: 6,
+            compress_trusted_proxies: true,
+            max_pending_bytes: 8 * 1024 * 1024,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            throttle_connections: 10,
+            throttle_window: 4,
+            max_connections_per_ip: 10,
+            max_pre_login: 512,
+            max_bytes_per_tick: 64 * 1024,
+            max_packets_per_tick: 100,
+            max_packet_size_login: 32 * 1024,
+            max_packet_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum ConnectionSettings {
+    Offline {
+        /// Don't ask clients to enable encryption.
+        #[serde(default)]
+        skip_encryption: bool,
+    },
+    Velocity {
+        secret: String,
+    },
+    BungeeCord {
+        /// Accepted BungeeGuard tokens, empty disables the check.
+        #[serde(default)]
+        bungeeguard_tokens: Vec<String>,
+    },
+    Online {
+        #[serde(default = "default_session_server")]
+        session_server: String,
+        #[serde(default)]
+        prevent_proxy_connections: bool,
+    },
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self::Offline { skip_encryption: false }
+    }
+}
+
+fn default_session_server() -> String {
+    MOJANG_SESSION_SERVER.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeepAliveSection {
+    /// Seconds between keepalive packets, a client that misses one is kicked.
+    pub period: u64,
+}
+
+impl Default for KeepAliveSection {
+    fn default() -> Self {
+        Self {
+            period: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VirtualHostsSection {
+    /// Refuse clients whose hostname isn't listed in `hosts` instead of
+    /// handing them the `server` and `connection` defaults.
+    pub reject_unknown: bool,
+    pub hosts: Vec<VirtualHostSection>,
+}
+
+/// Overrides for clients connecting through one of `hostnames`, unset keys
+/// fall back to the `server` and `connection` sections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VirtualHostSection {
+    /// Matched case-insensitively against the address the client typed.
+    pub hostnames: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<ConnectionSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub world: Option<String>,
+}
+
+/// GameSpy4 UDP query, used by server lists to read the player list.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuerySection {
+    pub enabled: bool,
+    pub address: SocketAddr,
+    /// Seconds between challenge token rotations, tokens stay valid for one to two of these.
+    pub challenge_interval: u64,
+}
+
+impl Default for QuerySection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: ([127, 0, 0, 1], 25565).into(),
+            challenge_interval: 30,
+        }
+    }
+}
+
+/// Source RCON remote console, all keys require a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RconSection {
+    pub enabled: bool,
+    pub address: SocketAddr,
+    pub password: String,
+    /// Further connections are closed right away.
+    pub max_sessions: usize,
+    /// Seconds before an idle session is closed.
+    pub idle_timeout: u64,
+}
+
+impl Default for RconSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: ([127, 0, 0, 1], 25575).into(),
+            password: String::new(),
+            max_sessions: 4,
+            idle_timeout: 600,
+        }
+    }
+}
+
+/// Vanilla `whitelist.json`, `banned-players.json` and `banned-ips.json`,
+/// managed with the `whitelist`, `ban`, `ban-ip`, `pardon` and `pardon-ip` commands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessSection {
+    /// Only players on the whitelist may join.
+    pub whitelist: bool,
+    pub whitelist_file: PathBuf,
+    pub banned_players_file: PathBuf,
+    pub banned_ips_file: PathBuf,
+}
+
+impl Default for AccessSection {
+    fn default() -> Self {
+        Self {
+            whitelist: false,
+            whitelist_file: PathBuf::from(DEFAULT_WHITELIST_PATH),
+            banned_players_file: PathBuf::from(DEFAULT_BANNED_PLAYERS_PATH),
+            banned_ips_file: PathBuf::from(DEFAULT_BANNED_IPS_PATH),
+        }
+    }
+}
+
+/// Seconds a client may spend in each phase before reaching play.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutSection {
+    pub handshake: u64,
+    /// Covers the status request and the ping.
+    pub status: u64,
+    pub login: u64,
+    pub config: u64,
+    /// Wait for each login plugin response, unanswered ones count as not understood.
+    pub login_query: u64,
+}
+
+impl Default for TimeoutSection {
+    fn default() -> Self {
+        Self {
+            handshake: 5,
+            status: 5,
+            login: 10,
+            config: 30,
+            login_query: 5,
+        }
+    }
+}
+
+impl Settings {
+    /// Path of the config file, `HYPERPUMPKIN_CONFIG` or [`DEFAULT_CONFIG_PATH`].
+    pub fn path() -> PathBuf {
+        std::env::var_os(ENV_CONFIG_PATH)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+    }
+
+    /// Loads the config file, creating it if it doesn't exist and writing back
+    /// any keys that are missing. Environment overrides are applied afterwards
+    /// and never written to disk.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut table = if path.exists() {
+            let content = fs::read_to_string(path)
+                .map_err(|source| ConfigError::Io { path: path.to_owned(), source })?;
+            content.parse::<Table>()
+                .map_err(|source| ConfigError::Parse { path: path.to_owned(), source })?
+        } else {
+            tracing::info!("Creating default config at {}", path.display());
+            Table::new()
+        };
+
+        let Value::Table(defaults) = Value::try_from(Settings::default())? else {
+            unreachable!("settings serialize to a table");
+        };
+        if merge_missing(&mut table, &defaults) {
+            let content = toml::to_string_pretty(&table)?;
+            fs::write(path, content)
+                .map_err(|source| ConfigError::Io { path: path.to_owned(), source })?;
+        }
+
+        apply_env(&mut table, std::env::vars())?;
+
+        let settings: Settings = Value::Table(table).try_into()
+            .map_err(|source| ConfigError::Parse { path: path.to_owned(), source })?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.max_players == 0 {
+            return Err(ConfigError::invalid("server.max_players", "must be at least 1"));
+        }
+        if self.server.threads == 0 {
+            return Err(ConfigError::invalid("server.threads", "must be at least 1"));
+        }
+        if !self.server.tps.is_finite() || self.server.tps <= 0.0 {
+            return Err(ConfigError::invalid("server.tps", "must be a positive number"));
+        }
+        if self.network.compression_threshold < -1 {
+            return Err(ConfigError::invalid("network.compression_threshold", "must be -1 (disabled) or higher"));
+        }
+        if self.network.compression_level > 9 {
+            return Err(ConfigError::invalid("network.compression_level", "must be between 0 and 9"));
+        }
+        if self.keepalive.period == 0 {
+            return Err(ConfigError::invalid("keepalive.period", "must be at least 1 second"));
+        }
+        let timeouts = [
+            ("timeouts.handshake", self.timeouts.handshake),
+            ("timeouts.status", self.timeouts.status),
+            ("timeouts.login", self.timeouts.login),
+            ("timeouts.config", self.timeouts.config),
+            ("timeouts.login_query", self.timeouts.login_query),
+        ];
+        for (key, timeout) in timeouts {
+            if timeout == 0 {
+                return Err(ConfigError::invalid(key, "must be at least 1 second"));
+            }
+        }
+        if self.network.max_pending_bytes == 0 {
+            return Err(ConfigError::invalid("network.max_pending_bytes", "must be at least 1"));
+        }
+        if self.network.throttle_connections != 0 && self.network.throttle_window == 0 {
+            return Err(ConfigError::invalid("network.throttle_window", "must be at least 1 second"));
+        }
+        if self.network.proxy_protocol && self.network.trusted_proxies.is_empty() {
+            return Err(ConfigError::invalid("network.trusted_proxies", "proxy_protocol requires at least one trusted proxy"));
+        }
+        if self.query.challenge_interval == 0 {
+            return Err(ConfigError::invalid("query.challenge_interval", "must be at least 1 second"));
+        }
+        if self.rcon.enabled && self.rcon.password.is_empty() {
+            return Err(ConfigError::invalid("rcon.password", "rcon requires a password"));
+        }
+        if self.rcon.idle_timeout == 0 {
+            return Err(ConfigError::invalid("rcon.idle_timeout", "must be at least 1 second"));
+        }
+        validate_connection(&self.connection, "connection")?;
+        if self.virtual_hosts.reject_unknown && self.virtual_hosts.hosts.is_empty() {
+            return Err(ConfigError::invalid("virtual_hosts.reject_unknown", "requires at least one virtual host"));
+        }
+        for (i, host) in self.virtual_hosts.hosts.iter().enumerate() {
+            if host.hostnames.is_empty() {
+                return Err(ConfigError::invalid(format!("virtual_hosts.hosts[{i}].hostnames"), "must not be empty"));
+            }
+            if let Some(connection) = &host.connection {
+                validate_connection(connection, &format!("virtual_hosts.hosts[{i}].connection"))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn connection_mode(&self) -> ConnectionMode {
+        connection_mode(&self.connection)
+    }
+
+    /// Builds the [`ServerConfig`] resource, reading the favicons from disk.
+    pub fn server_config(&self) -> Result<ServerConfig, ConfigError> {
+        let default_host = VirtualHost {
+            description: parse_description(&self.server.description, "server.description")?,
+            favicon: load_favicon(&self.server.favicon, "server.favicon")?,
+            connection_mode: self.connection_mode(),
+            world: self.server.world.clone(),
+        };
+
+        let mut virtual_hosts = HashMap::new();
+        for (i, section) in self.virtual_hosts.hosts.iter().enumerate() {
+            let host = Arc::new(VirtualHost {
+                description: match &section.description {
+                    Some(description) => parse_description(description, &format!("virtual_hosts.hosts[{i}].description"))?,
+                    None => default_host.description.clone(),
+                },
+                favicon: match &section.favicon {
+                    Some(favicon) => load_favicon(favicon, &format!("virtual_hosts.hosts[{i}].favicon"))?,
+                    None => default_host.favicon.clone(),
+                },
+                connection_mode: section.connection.as_ref()
+                    .map_or_else(|| default_host.connection_mode.clone(), connection_mode),
+                world: section.world.clone().unwrap_or_else(|| default_host.world.clone()),
+            });
+            for hostname in &section.hostnames {
+                virtual_hosts.insert(hostname.to_ascii_lowercase(), host.clone());
+            }
+        }
+
+        Ok(ServerConfig {
+            max_players: self.server.max_players,
+            default_host,
+            virtual_hosts,
+            reject_unknown_hosts: self.virtual_hosts.reject_unknown,
+            compression_threshold: self.network.compression_threshold,
+            compression_level: self.network.compression_level,
+            compress_trusted_proxies: self.network.compress_trusted_proxies,
+            player_limit_bypass: self.server.player_limit_bypass.clone(),
+            duplicate_login: self.server.duplicate_login,
+            legacy_offline_uuids: self.server.legacy_offline_uuids,
+        })
+    }
+
+    pub fn keepalive_settings(&self) -> KeepAliveSettings {
+        KeepAliveSettings {
+            period: Duration::from_secs(self.keepalive.period),
+        }
+    }
+
+    pub fn phase_timeouts(&self) -> PhaseTimeouts {
+        PhaseTimeouts {
+            handshake: Duration::from_secs(self.timeouts.handshake),
+            status: Duration::from_secs(self.timeouts.status),
+            login: Duration::from_secs(self.timeouts.login),
+            config: Duration::from_secs(self.timeouts.config),
+            login_query: Duration::from_secs(self.timeouts.login_query),
+        }
+    }
+
+    pub fn query_settings(&self) -> QuerySettings {
+        QuerySettings {
+            address: self.query.address,
+            challenge_interval: Duration::from_secs(self.query.challenge_interval),
+        }
+    }
+
+    pub fn rcon_settings(&self) -> RconSettings {
+        RconSettings {
+            address: self.rcon.address,
+            password: self.rcon.password.clone(),
+            max_sessions: self.rcon.max_sessions,
+            idle_timeout: Duration::from_secs(self.rcon.idle_timeout),
+        }
+    }
+
+    pub fn key_settings(&self) -> KeySettings {
+        KeySettings {
+            path: self.server.key_file.clone(),
+        }
+    }
+
+    pub fn access_settings(&self) -> AccessSettings {
+        AccessSettings {
+            whitelist: self.access.whitelist,
+            whitelist_file: self.access.whitelist_file.clone(),
+            banned_players_file: self.access.banned_players_file.clone(),
+            banned_ips_file: self.access.banned_ips_file.clone(),
+        }
+    }
+
+    pub fn shutdown_settings(&self) -> ShutdownSettings {
+        ShutdownSettings {
+            message: self.server.shutdown_message.clone(),
+            timeout: Duration::from_secs(self.server.shutdown_timeout),
+        }
+    }
+
+    /// Resets keys of `new` that can only be applied by restarting back to
+    /// the running values and returns the ones that differed.
+    pub fn retain_restart_only(&self, new: &mut Settings) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.network.address != new.network.address {
+            new.network.address = self.network.address;
+            keys.push("network.address");
+        }
+        if self.network.proxy_protocol != new.network.proxy_protocol {
+            new.network.proxy_protocol = self.network.proxy_protocol;
+            keys.push("network.proxy_protocol");
+        }
+        if self.network.trusted_proxies != new.network.trusted_proxies {
+            new.network.trusted_proxies = self.network.trusted_proxies.clone();
+            keys.push("network.trusted_proxies");
+        }
+        if self.query.enabled != new.query.enabled {
+            new.query.enabled = self.query.enabled;
+            keys.push("query.enabled");
+        }
+        if self.query.address != new.query.address {
+            new.query.address = self.query.address;
+            keys.push("query.address");
+        }
+        if self.rcon != new.rcon {
+            new.rcon = self.rcon.clone();
+            keys.push("rcon");
+        }
+        if self.server.key_file != new.server.key_file {
+            new.server.key_file = self.server.key_file.clone();
+            keys.push("server.key_file");
+        }
+        let files = (&self.access.whitelist_file, &self.access.banned_players_file, &self.access.banned_ips_file);
+        if files != (&new.access.whitelist_file, &new.access.banned_players_file, &new.access.banned_ips_file) {
+            new.access = AccessSection {
+                whitelist: new.access.whitelist,
+                ..self.access.clone()
+            };
+            keys.push("access");
+        }
+        if self.server.threads != new.server.threads {
+            new.server.threads = self.server.threads;
+            keys.push("server.threads");
+        }
+        keys
+    }
+
+    pub fn network_settings(&self) -> NetworkSettings {
+        NetworkSettings {
+            address: self.network.address,
+            max_pending_bytes: self.network.max_pending_bytes,
+            proxy_protocol: self.network.proxy_protocol,
+            trusted_proxies: self.network.trusted_proxies.clone(),
+            throttle_connections: self.network.throttle_connections,
+            throttle_window: Duration::from_secs(self.network.throttle_window),
+            max_connections_per_ip: self.network.max_connections_per_ip,
+            max_pre_login: self.network.max_pre_login,
+            handshake_timeout: Duration::from_secs(self.timeouts.handshake),
+            max_bytes_per_tick: self.network.max_bytes_per_tick,
+            max_packets_per_tick: self.network.max_packets_per_tick,
+            max_packet_size_login: self.network.max_packet_size_login,
+            max_packet_size: self.network.max_packet_size,
+        }
+    }
+
+}
+
+fn validate_connection(connection: &ConnectionSettings, key: &str) -> Result<(), ConfigError> {
+    match connection {
+        ConnectionSettings::Velocity { secret } if secret.is_empty() => {
+            Err(ConfigError::invalid(format!("{key}.secret"), "velocity requires a forwarding secret"))
+        },
+        ConnectionSettings::Online { session_server, .. } if session_server.is_empty() => {
+            Err(ConfigError::invalid(format!("{key}.session_server"), "must not be empty"))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn connection_mode(connection: &ConnectionSettings) -> ConnectionMode {
+    match connection {
+        ConnectionSettings::Offline { skip_encryption } => ConnectionMode::Offline {
+            skip_encryption: *skip_encryption,
+        },
+        ConnectionSettings::Velocity { secret } => ConnectionMode::Velocity {
+            secret: Arc::from(secret.as_str()),
+        },
+        ConnectionSettings::BungeeCord { bungeeguard_tokens } => ConnectionMode::BungeeCord {
+            guard_tokens: Arc::from(bungeeguard_tokens.as_slice()),
+        },
+        ConnectionSettings::Online { session_server, prevent_proxy_connections } => ConnectionMode::Online {
+            session: Arc::new(HttpSessionServer::new(session_server.as_str())),
+            prevent_proxy_connections: *prevent_proxy_connections,
+        },
+    }
+}
+
+/// Plain text, or a JSON text component if it starts with `{` or `[`.
+fn parse_description(description: &str, key: &str) -> Result<Text, ConfigError> {
+    let trimmed = description.trim_start();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        serde_json::from_str(trimmed).map_err(|err| ConfigError::invalid(key, err.to_string()))
+    } else {
+        Ok(Text::text(description.to_string()))
+    }
+}
+
+/// Reads the png at `path` into a data URL, the built-in icon if it's empty.
+fn load_favicon(path: &str, key: &str) -> Result<String, ConfigError> {
+    let bytes = if path.is_empty() {
+        include_bytes!("../../icon.png").to_vec()
+    } else {
+        let path = PathBuf::from(path);
+        fs::read(&path).map_err(|source| ConfigError::Io { path, source })?
+    };
+    validate_favicon(&bytes).map_err(|reason| ConfigError::invalid(key, reason))?;
+
+    let base64 = general_purpose::STANDARD.encode(bytes);
+    Ok(format!("data:image/png;base64,{}", base64))
+}
+
+/// Checks the png header, clients only show 64x64 favicons.
+fn validate_favicon(bytes: &[u8]) -> Result<(), String> {
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    // the IHDR chunk always comes first, width and height are its first fields
+    if bytes.len() < 24 || !bytes.starts_with(PNG_SIGNATURE) || &bytes[12..16] != b"IHDR" {
+        return Err("not a png image".to_string());
+    }
+    let width = u32::from_be_bytes(bytes[16..20].try_into().expect("4 bytes"));
+    let height = u32::from_be_bytes(bytes[20..24].try_into().expect("4 bytes"));
+    if (width, height) != (64, 64) {
+        return Err(format!("must be 64x64 pixels, got {width}x{height}"));
+    }
+    Ok(())
+}
+
+/// Inserts every key of `defaults` that is missing from `table`.
+/// Returns true if anything was inserted.
+fn merge_missing(table: &mut Table, defaults: &Table) -> bool {
+    let mut changed = false;
+    for (key, default) in defaults {
+        if !table.contains_key(key) {
+            table.insert(key.clone(), default.clone());
+            changed = true;
+        } else if let (Some(Value::Table(table)), Value::Table(default)) = (table.get_mut(key), default) {
+            // the default's fields belong to its own variant of a tagged enum
+            if table.get(ENUM_TAG) != default.get(ENUM_TAG) {
+                continue;
+            }
+            changed |= merge_missing(table, default);
+        }
+    }
+    changed
+}
+
+fn apply_env(table: &mut Table, vars: impl Iterator<Item = (String, String)>) -> Result<(), ConfigError> {
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else { continue; };
+        if name == ENV_CONFIG_PATH {
+            continue;
+        }
+
+        let path: Vec<String> = key.split("__").map(|part| part.to_lowercase()).collect();
+        if path.iter().any(String::is_empty) {
+            return Err(ConfigError::Env { name, reason: "empty key segment".to_string() });
+        }
+
+        let (last, parents) = path.split_last().expect("split yields at least one segment");
+        let mut current = &mut *table;
+        for parent in parents {
+            let entry = current
+                .entry(parent.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            let Value::Table(next) = entry else {
+                return Err(ConfigError::Env { name, reason: format!("`{parent}` is not a table") });
+            };
+            current = next;
+        }
+
+        // Values are parsed as toml so numbers and booleans keep their type,
+        // anything else is taken as a plain string. Keys that already hold a
+        // string keep the raw value, secrets can look like numbers too.
+        let value = match current.get(last) {
+            Some(Value::String(_)) => Value::String(raw),
+            _ => format!("value = {raw}")
+                .parse::<Table>()
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(Value::String(raw)),
+        };
+        current.insert(last.clone(), value);
+    }
+    Ok(())
+}
@@ -1,7 +1,8 @@
-use std::io;
+use std::{io, path::PathBuf};
 
 use pumpkin_protocol::{bytebuf::DeserializerError, PacketError};
 use thiserror::Error;
+use valence_text::Text;
 
 
 #[derive(Debug, Error)]
@@ -18,6 +19,63 @@ pub enum PacketIoError {
     BadPacket(&'static str),
     #[error("disconnect")]
     Disconnect,
+    /// The connection was turned away with this reason, no further packets are handled.
+    #[error("refused: {0}")]
+    Refused(String),
     #[error("anyhow: {0}")]
-    Anyhow(#[from] anyhow::Error)
-}
\ No newline at end of file
+    Anyhow(anyhow::Error)
+}
+
+impl From<anyhow::Error> for PacketIoError {
+    /// Keeps the disconnect reason of errors raised as a `PacketIoError`.
+    fn from(err: anyhow::Error) -> Self {
+        err.downcast().unwrap_or_else(PacketIoError::Anyhow)
+    }
+}
+
+impl PacketIoError {
+    /// Message shown to the player on the disconnect screen.
+    pub fn reason(&self) -> Text {
+        match self {
+            PacketIoError::Packet(_) | PacketIoError::Deserializer(_) => Text::text("Received an invalid packet"),
+            PacketIoError::BadPacket(msg) => Text::text(format!("Received an invalid packet: {msg}")),
+            PacketIoError::Io(_) => Text::text("Connection error"),
+            PacketIoError::Rsa(_) => Text::text("Failed to set up encryption"),
+            PacketIoError::Disconnect => Text::text("Disconnected"),
+            PacketIoError::Refused(msg) => Text::text(msg.clone()),
+            PacketIoError::Anyhow(_) => Text::text("Failed to log in"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to access {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    #[error("failed to parse {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("failed to serialize config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("invalid environment override {name}: {reason}")]
+    Env {
+        name: String,
+        reason: String,
+    },
+    #[error("invalid value for `{key}`: {reason}")]
+    Invalid {
+        key: String,
+        reason: String,
+    },
+}
+
+impl ConfigError {
+    pub fn invalid(key: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Invalid { key: key.into(), reason: reason.into() }
+    }
+}
@@ -2,7 +2,7 @@ use flecs_ecs::core::EntityView;
 use pumpkin_protocol::{bytebuf::packet_id::Packet, client::config::{CFinishConfig, CRegistryData}, server::config::{SAcknowledgeFinishConfig, SClientInformationConfig, SKnownPacks, SPluginMessage}, RawPacket, ServerPacket};
 use pumpkin_registry::Registry;
 
-use crate::{components::{client::{ConfigState, CurrentState, PacketEncoder}, player::{ClientBrand, Play}}, error::PacketIoError, handlers::play::on_play};
+use crate::{components::{client::{ConfigState, CurrentState, PacketEncoder}, player::{ClientBrand, HiddenFromStatus, Play}}, error::PacketIoError, handlers::play::on_play};
 
 pub fn config_handler(
     e: EntityView,
@@ -30,7 +30,10 @@ pub fn config_handler(
         SClientInformationConfig::PACKET_ID => {
             if *config == ConfigState::KnownPacks {
                 // tracing::info!("Optional ClientInfo packet received");
-                let _packet = SClientInformationConfig::read(&mut packet.bytebuf)?;
+                let packet = SClientInformationConfig::read(&mut packet.bytebuf)?;
+                if !packet.server_listing {
+                    e.add::<HiddenFromStatus>();
+                }
             } else {
                 tracing::warn!("Out of order packet: Expected {:?}, received KnownPacks", config);
             }
@@ -54,7 +57,7 @@ pub fn config_handler(
                 e.remove::<CurrentState>();
                 e.add::<Play>();
 
-                on_play(enc)?;
+                on_play(e, enc)?;
             } else {
                 tracing::warn!("Out of order packet: Expected {:?}, received AckFinish", config);
             }
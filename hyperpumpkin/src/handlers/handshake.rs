@@ -1,8 +1,14 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use flecs_ecs::core::EntityView;
 use lazy_static::lazy_static;
 use pumpkin_protocol::{client::login::CLoginDisconnect, server::handshake::SHandShake, ConnectionState, RawPacket, ServerPacket};
 use valence_text::Text;
 
-use crate::{components::client::CurrentState, error::PacketIoError, PacketEncoder};
+use crate::{components::{client::{CurrentState, ForwardedProfile, Hostname, RemoteAddress}, player::ProfileProperty, resources::{ConnectionMode, ServerConfig, VirtualHost}}, error::PacketIoError, PacketEncoder};
+
+const BUNGEEGUARD_TOKEN_PROPERTY: &str = "bungeeguard-token";
 
 lazy_static! {
     pub static ref REASON: String = {
@@ -11,17 +17,94 @@ lazy_static! {
     };
 }
 
-pub fn handshake_handler(
+/// What the handshake resolved. The components it sets only show up once the
+/// system merges, packets after it in the same queue use this instead.
+pub struct Handshake<'a> {
+    /// The virtual host the client connected through.
+    pub host: &'a VirtualHost,
+    /// Sent by a BungeeCord proxy on login.
+    pub forwarded: Option<ForwardedProfile>,
+}
+
+pub fn handshake_handler<'a>(
+    e: EntityView,
     mut packet: RawPacket,
     enc: &mut PacketEncoder,
-    state: &mut CurrentState
-) -> Result<(), PacketIoError> {
+    state: &mut CurrentState,
+    config: &'a ServerConfig,
+) -> Result<Handshake<'a>, PacketIoError> {
     let handshake = SHandShake::read(&mut packet.bytebuf)?;
     if handshake.next_state == ConnectionState::Login && handshake.protocol_version != pumpkin_protocol::CURRENT_MC_PROTOCOL.into() {
         enc.append_packet(&CLoginDisconnect::new(&REASON))?;
 
         return Err(PacketIoError::Disconnect)
     }
+
     *state = handshake.next_state.into();
-    Ok(())
+
+    let hostname = normalize_hostname(&handshake.server_address);
+    let Some(host) = config.host(&hostname) else {
+        tracing::info!("Rejected {e}, unknown hostname {hostname:?}");
+        return Err(PacketIoError::Refused("Unknown hostname".to_string()));
+    };
+    e.set(Hostname(hostname));
+
+    let mut forwarded = None;
+    if handshake.next_state == ConnectionState::Login {
+        if let ConnectionMode::BungeeCord { guard_tokens } = &host.connection_mode {
+            match parse_bungee_forwarding(&handshake.server_address, guard_tokens) {
+                Ok(profile) => {
+                    e.set(RemoteAddress(profile.address));
+                    e.set(profile.clone());
+                    forwarded = Some(profile);
+                },
+                Err(err) => {
+                    tracing::warn!("Rejected BungeeCord forwarding from {e}: {}", err);
+                    return Err(PacketIoError::Refused("If you wish to use IP forwarding, please enable it in your BungeeCord config as well!".to_string()));
+                },
+            }
+        }
+    }
+
+    Ok(Handshake { host, forwarded })
+}
+
+/// The typed hostname, without BungeeCord forwarding data or Forge markers
+/// after a `\0`, and without the trailing dot of a fully qualified name.
+fn normalize_hostname(server_address: &str) -> String {
+    server_address
+        .split('\0')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+/// Legacy BungeeCord forwarding puts `host\0ip\0uuid\0properties` into the
+/// handshake's server address. With BungeeGuard the properties carry a token
+/// that has to match one of `guard_tokens`.
+fn parse_bungee_forwarding(server_address: &str, guard_tokens: &[String]) -> anyhow::Result<ForwardedProfile> {
+    let mut parts = server_address.split('\0');
+    let _host = parts.next();
+    let address: IpAddr = parts.next()
+        .context("missing forwarded address")?
+        .parse()
+        .context("invalid forwarded address")?;
+    let uuid = uuid::Uuid::parse_str(parts.next().context("missing forwarded uuid")?)
+        .context("invalid forwarded uuid")?;
+    let mut properties: Vec<ProfileProperty> = match parts.next() {
+        Some(json) => serde_json::from_str(json).context("invalid forwarded properties")?,
+        None => Vec::new(),
+    };
+
+    if !guard_tokens.is_empty() {
+        let mut tokens = properties.iter().filter(|property| property.name == BUNGEEGUARD_TOKEN_PROPERTY);
+        let token = tokens.next().context("missing bungeeguard token")?;
+        anyhow::ensure!(tokens.next().is_none(), "multiple bungeeguard tokens");
+        anyhow::ensure!(guard_tokens.contains(&token.value), "invalid bungeeguard token");
+    }
+    // never hand the token to clients
+    properties.retain(|property| property.name != BUNGEEGUARD_TOKEN_PROPERTY);
+
+    Ok(ForwardedProfile { address, uuid, properties })
 }
\ No newline at end of file
@@ -1,19 +1,23 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::Arc};
 
 use anyhow::{bail, ensure, Context};
 use bytes::BytesMut;
 use flecs_ecs::core::EntityView;
 use hmac::{Hmac, Mac};
 use pumpkin_config::compression::CompressionInfo;
-use pumpkin_protocol::{bytebuf::ByteBuffer, client::{config::CKnownPacks, login::{CEncryptionRequest, CLoginPluginRequest, CLoginSuccess, CSetCompression}}, packet_decoder::PacketDecoder, server::login::{SEncryptionResponse, SLoginAcknowledged, SLoginPluginResponse, SLoginStart}, KnownPack, Property, RawPacket, ServerPacket, VarInt};
+use pumpkin_protocol::{bytebuf::ByteBuffer, client::{config::CKnownPacks, login::{CEncryptionRequest, CLoginSuccess, CSetCompression}}, server::login::{SEncryptionResponse, SLoginAcknowledged, SLoginPluginResponse, SLoginStart}, KnownPack, Property, RawPacket, ServerPacket, VarInt};
 use rand::Rng;
-use rsa::{pkcs8::Document, Pkcs1v15Encrypt};
+use rsa::Pkcs1v15Encrypt;
 use sha2::Sha256;
 
-use crate::{components::{client::{ConfigState, CurrentState, LoginState, PacketEncoder, RemoteAddress}, player::Uuid, resources::{ConnectionMode, KeyPair}}, error::PacketIoError};
+use crate::{auth::{server_hash, spawn_has_joined}, components::{client::{ConfigState, CurrentState, Disconnect, ForwardedProfile, LoginState, PacketDecoder, PacketEncoder, PendingAuth, RemoteAddress, TrustedProxy}, player::{PlayerPublicKey, ProfileProperties, ProfileProperty, SpawnWorld, Username, Uuid}, resources::{ConnectionMode, DuplicateLogin, KeyPair, ServerConfig, ServerStorage, VirtualHost}}, error::PacketIoError, modules::{login_refusal, LoginChannels, LoginProfile, LoginQueries, LoginQueryContext}};
 
-const VELOCITY_MIN_SUPPORTED_VERSION: u8 = 1;
+const VELOCITY_MODERN_FORWARDING_DEFAULT: i32 = 1;
+const VELOCITY_MODERN_FORWARDING_WITH_KEY: i32 = 2;
 const VELOCITY_MODERN_FORWARDING_WITH_KEY_V2: i32 = 3;
+const VELOCITY_MODERN_LAZY_SESSION: i32 = 4;
+const VELOCITY_MAX_SUPPORTED_VERSION: i32 = VELOCITY_MODERN_LAZY_SESSION;
+const VELOCITY_CHANNEL: &str = "velocity:player_info";
 
 pub fn login_handler(
     mut packet: RawPacket,
@@ -21,101 +25,72 @@ pub fn login_handler(
     dec: &mut PacketDecoder,
     state: &mut CurrentState,
     key_pair: &KeyPair,
-    mode: &ConnectionMode,
+    host: &VirtualHost,
+    forwarded: &mut Option<ForwardedProfile>,
+    config: &ServerConfig,
+    storage: &ServerStorage,
     e: EntityView,
 ) -> anyhow::Result<()> {
     let CurrentState::Login(login) = state else { unreachable!(); };
+    let mode = &host.connection_mode;
 
     match login {
         LoginState::LoginStart => {
             let packet = SLoginStart::read(&mut packet.bytebuf)?;
-            
+            if !valid_username(&packet.name) {
+                tracing::info!("Refused invalid username {:?}", packet.name);
+                bail!(PacketIoError::Refused("Invalid username".to_string()));
+            }
+
             match mode {
                 ConnectionMode::Velocity { .. } => {
-                    login_velocity(enc, packet.name, login)?;
+                    let mut queries = LoginQueries::default();
+                    queries.send(enc, VELOCITY_CHANNEL, &[VELOCITY_MAX_SUPPORTED_VERSION as u8], Arc::new(velocity_response))?;
+                    *login = LoginState::Queries {
+                        username: packet.name,
+                        profile: None,
+                        queries,
+                    };
                 },
-                ConnectionMode::Offline => {
-                    login_offline(enc, packet.name, &key_pair.public_bytes, login)?;
+                ConnectionMode::Offline { skip_encryption: true } => {
+                    let profile = LoginProfile {
+                        uuid: offline_uuid(&packet.name, config.legacy_offline_uuids),
+                        properties: Vec::new(),
+                        address: None,
+                    };
+                    begin_queries(e, enc, dec, host, config, storage, packet.name, profile, login)?;
                 },
-            }
-        },
-        LoginState::VelocityResponse { message_id, username } => {
-            let packet = SLoginPluginResponse::read(&mut packet.bytebuf)?;
-            
-            ensure!(
-                packet.message_id.0 == *message_id,
-                "mismatched plugin response ID (got {}, expected {message_id})",
-                packet.message_id.0,
-            );
-            
-            let data = packet
-                .data
-                .context("missing plugin response data")?;
-            
-            ensure!(data.len() >= 32, "invalid plugin response data length");
-            let (signature, data_without_signature) = data.split_at(32);
-            
-            let ConnectionMode::Velocity { secret } = mode else { bail!("invalid state"); };
+                ConnectionMode::Offline { skip_encryption: false } => {
+                    request_encryption(enc, packet.name, key_pair, false, login)?;
+                },
+                ConnectionMode::Online { .. } => {
+                    request_encryption(enc, packet.name, key_pair, true, login)?;
+                },
+                ConnectionMode::BungeeCord { .. } => {
+                    // from the handshake earlier in this queue, or in an earlier tick
+                    let forwarded = match forwarded.take() {
+                        Some(forwarded) => forwarded,
+                        None => e.try_get::<&ForwardedProfile>(ForwardedProfile::clone)
+                            .context("missing bungeecord forwarding data")?,
+                    };
+                    e.remove::<ForwardedProfile>();
 
-            // Verify signature
-            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
-            Mac::update(&mut mac, data_without_signature);
-            mac.verify_slice(&signature)?;
+                    let profile = LoginProfile {
+                        uuid: forwarded.uuid,
+                        properties: forwarded.properties,
+                        address: Some(forwarded.address),
+                    };
 
-            let mut data_without_signature = ByteBuffer::new(BytesMut::from(data_without_signature));
-            
-            // Check Velocity version
-            let version = data_without_signature.get_var_int()
-                .context("failed to decode velocity version")?
-                .0;
-            
-            // Get client address
-            let remote_addr: IpAddr = data_without_signature.get_string()?.parse()?;
-            
-            // Get UUID
-            let uuid = data_without_signature.get_uuid()?;
-            
-            // Get username and validate
-            let name = data_without_signature.get_string()?;
-            ensure!(
-                username == &name,
-                "mismatched usernames"
-            );
-        
-            // Read game profile properties
-            let _properties = data_without_signature.get_list(|data| {
-                let name = data.get_string()?;
-                let value = data.get_string()?;
-                let signature = data.get_option(|data| {
-                    data.get_string()
-                })?;
-
-                Ok(Property {
-                    name,
-                    value,
-                    signature,
-                })
-            }).context("decoding velocity game profile properties")?;
-        
-            if version >= VELOCITY_MODERN_FORWARDING_WITH_KEY_V2 {
-                // TODO
+                    begin_queries(e, enc, dec, host, config, storage, packet.name, profile, login)?;
+                },
             }
-
-            e.set(Uuid(uuid));
-            e.set(RemoteAddress(remote_addr));
-
-            setup_compression(256, enc, dec)?;
-
-            enc.append_packet(&CLoginSuccess::new(
-                &uuid,
-                &username,
-                &[],
-                true,
-            ))?;
-            
-            *login = LoginState::LoginAck;
         },
-        LoginState::EncryptionResponse { verify_token, uuid, username  } => {
+        LoginState::Queries { .. } => {
+            let packet = SLoginPluginResponse::read(&mut packet.bytebuf)?;
+            answer_query(e, host, config, login, packet.message_id.0, packet.data.as_deref())?;
+            advance_queries(e, enc, dec, host, config, storage, login)?;
+        },
+        LoginState::EncryptionResponse { verify_token, username, key_pair } => {
             let packet = SEncryptionResponse::read(&mut packet.bytebuf)?;
 
             let shared_secret = key_pair.private.decrypt(Pkcs1v15Encrypt, &packet.shared_secret)?;
@@ -132,16 +107,29 @@ pub fn login_handler(
             enc.set_encryption(Some(shared_secret));
             dec.set_encryption(Some(shared_secret));
 
-            setup_compression(256, enc, dec)?;
+            let username = std::mem::take(username);
+            if let ConnectionMode::Online { session, prevent_proxy_connections } = mode {
+                let hash = server_hash("", shared_secret, key_pair.public_bytes.as_bytes());
+                let ip = if *prevent_proxy_connections {
+                    e.get::<&RemoteAddress>(|addr| Some(addr.0))
+                } else {
+                    None
+                };
 
-            enc.append_packet(&CLoginSuccess::new(
-                &uuid,
-                &username,
-                &[],
-                true,
-            ))?;
-            
-            *login = LoginState::LoginAck;
+                e.set(PendingAuth(spawn_has_joined(session.clone(), username.clone(), hash, ip)));
+                *login = LoginState::Authenticating { username };
+                return Ok(());
+            }
+
+            let profile = LoginProfile {
+                uuid: offline_uuid(&username, config.legacy_offline_uuids),
+                properties: Vec::new(),
+                address: None,
+            };
+            begin_queries(e, enc, dec, host, config, storage, username, profile, login)?;
+        },
+        LoginState::Authenticating { .. } => {
+            bail!(PacketIoError::BadPacket("unexpected packet while authenticating"));
         },
         LoginState::LoginAck => {
             let _ = SLoginAcknowledged::read(&mut packet.bytebuf)?;
@@ -158,53 +146,303 @@ pub fn login_handler(
     Ok(())
 }
 
-fn setup_compression(threshold: i32, enc: &mut PacketEncoder, dec: &mut PacketDecoder) -> anyhow::Result<()> {
-    enc.append_packet(&CSetCompression::new(VarInt(threshold)))?;
+/// Starts the login plugin queries registered in [`LoginChannels`] for a
+/// player whose profile is known, logging them in right away if there are none.
+pub fn begin_queries(
+    e: EntityView,
+    enc: &mut PacketEncoder,
+    dec: &mut PacketDecoder,
+    host: &VirtualHost,
+    config: &ServerConfig,
+    storage: &ServerStorage,
+    username: String,
+    profile: LoginProfile,
+    login: &mut LoginState,
+) -> anyhow::Result<()> {
+    *login = LoginState::Queries {
+        username,
+        profile: Some(profile),
+        queries: LoginQueries::default(),
+    };
+    advance_queries(e, enc, dec, host, config, storage, login)
+}
+
+/// Runs the handler of an outstanding login plugin query. `data` is `None`
+/// if the client didn't understand the channel or didn't answer in time.
+pub fn answer_query(e: EntityView, host: &VirtualHost, config: &ServerConfig, login: &mut LoginState, message_id: i32, data: Option<&[u8]>) -> anyhow::Result<()> {
+    let LoginState::Queries { username, profile, queries } = login else {
+        bail!(PacketIoError::BadPacket("unexpected plugin response"));
+    };
+    let Some((channel, handler)) = queries.take(message_id) else {
+        bail!(PacketIoError::BadPacket("unknown plugin response id"));
+    };
 
-    if threshold >= 0 {
-        enc.set_compression(Some(CompressionInfo {
-            threshold: threshold as u32,
-            level: 6,
-        }));
-        dec.set_compression(true);
+    let mut ctx = LoginQueryContext {
+        entity: e,
+        host,
+        config,
+        username: username.as_str(),
+        profile,
+    };
+    handler(&mut ctx, data).with_context(|| format!("login channel {channel}"))
+}
+
+/// Once nothing is outstanding, sends the registered channels if that didn't
+/// happen yet, then finishes the login when they are answered too.
+pub fn advance_queries(
+    e: EntityView,
+    enc: &mut PacketEncoder,
+    dec: &mut PacketDecoder,
+    host: &VirtualHost,
+    config: &ServerConfig,
+    storage: &ServerStorage,
+    login: &mut LoginState,
+) -> anyhow::Result<()> {
+    let LoginState::Queries { username, profile, queries } = login else { return Ok(()); };
+    if !queries.is_empty() {
+        return Ok(());
+    }
+    ensure!(profile.is_some(), "missing forwarding data");
+
+    if !queries.channels_sent {
+        queries.channels_sent = true;
+        let ctx = LoginQueryContext {
+            entity: e,
+            host,
+            config,
+            username: username.as_str(),
+            profile: &mut *profile,
+        };
+        e.world().get::<Option<&LoginChannels>>(|channels| match channels {
+            Some(channels) => channels.send_all(&ctx, enc, queries),
+            None => Ok(()),
+        })?;
+        if !queries.is_empty() {
+            return Ok(());
+        }
     }
 
+    let Some(profile) = profile.take() else { bail!("missing profile"); };
+    let username = std::mem::take(username);
+    finish_login(e, enc, dec, host, config, storage, profile, username)?;
+    *login = LoginState::LoginAck;
     Ok(())
 }
 
-fn login_offline(enc: &mut PacketEncoder, username: String, public_bytes: &Document, login: &mut LoginState) -> anyhow::Result<()> {
+/// Enables compression, sends `CLoginSuccess` and stores the player's profile.
+/// Fails with [`PacketIoError::Refused`] instead if the player is banned or not
+/// whitelisted, the server is full, or the player is already online and
+/// `duplicate_login` keeps the existing session.
+fn finish_login(
+    e: EntityView,
+    enc: &mut PacketEncoder,
+    dec: &mut PacketDecoder,
+    host: &VirtualHost,
+    config: &ServerConfig,
+    storage: &ServerStorage,
+    profile: LoginProfile,
+    username: String,
+) -> anyhow::Result<()> {
+    let LoginProfile { uuid, properties, address } = profile;
+
+    // the address a proxy forwarded in this tick isn't visible on the entity yet
+    let address = address.or_else(|| e.try_get::<&RemoteAddress>(|addr| addr.0));
+    if let Some(reason) = login_refusal(e.world(), &uuid, &username, address) {
+        tracing::info!("Refused {username}: {}", reason.replace('\n', " "));
+        bail!(PacketIoError::Refused(reason));
+    }
+
+    // held until this login is claimed, logins handled in parallel would
+    // otherwise both pass the checks below before either is counted
+    let mut players = storage.players.lock();
+
+    // counted from login success, players still configuring take a slot too
+    let replaces = players.contains_key(&uuid);
+    if !replaces && players.len() >= config.max_players && !config.bypasses_player_limit(&uuid, &username) {
+        tracing::info!("Refused {username}, the server is full");
+        bail!(PacketIoError::Refused("The server is full!".to_string()));
+    }
+
+    if let Some(&existing) = players.get(&uuid) {
+        let existing = e.world().entity_from_id(existing);
+        if existing.id() != e.id() && existing.is_alive() && !existing.has::<Disconnect>() {
+            match config.duplicate_login {
+                DuplicateLogin::KickExisting => {
+                    tracing::info!("{username} logged in again, kicking the existing session");
+                    existing.set(Disconnect::new("You logged in from another location"));
+                },
+                DuplicateLogin::RefuseNew => {
+                    tracing::info!("Refused {username}, already logged in");
+                    bail!(PacketIoError::Refused("You are already logged in".to_string()));
+                },
+            }
+        }
+    }
+
+    let threshold = if !config.compress_trusted_proxies && e.has::<TrustedProxy>() {
+        -1
+    } else {
+        config.compression_threshold
+    };
+    setup_compression(threshold, config.compression_level, enc, dec)?;
+
+    let wire_properties: Vec<Property> = properties.iter().cloned().map(Property::from).collect();
+    enc.append_packet(&CLoginSuccess::new(
+        &uuid,
+        &username,
+        &wire_properties,
+        true,
+    ))?;
+
+    players.insert(uuid, e.id());
+    drop(players);
+
+    e.set(Uuid(uuid));
+    e.set(Username(username));
+    e.set(ProfileProperties(properties));
+    e.set(SpawnWorld(host.world.clone()));
+    Ok(())
+}
+
+/// A negative `threshold` leaves the connection uncompressed.
+fn setup_compression(threshold: i32, level: u32, enc: &mut PacketEncoder, dec: &mut PacketDecoder) -> anyhow::Result<()> {
+    if threshold < 0 {
+        return Ok(());
+    }
+
+    enc.append_packet(&CSetCompression::new(VarInt(threshold)))?;
+    enc.set_compression(Some(CompressionInfo {
+        threshold: threshold as u32,
+        level,
+    }));
+    dec.set_compression(true);
+
+    Ok(())
+}
+
+fn request_encryption(
+    enc: &mut PacketEncoder,
+    username: String,
+    key_pair: &KeyPair,
+    should_authenticate: bool,
+    login: &mut LoginState,
+) -> anyhow::Result<()> {
     let mut verify_token = [0u8; 4];
     rand::thread_rng().fill(&mut verify_token);
     
     enc.append_packet(&CEncryptionRequest::new(
         "",
-        public_bytes.as_bytes(),
+        key_pair.public_bytes.as_bytes(),
         &verify_token,
-        false,
+        should_authenticate,
     ))?;
 
     *login = LoginState::EncryptionResponse {
         verify_token,
-        uuid: offline_uuid(&username),
-        username
+        username,
+        key_pair: key_pair.clone(),
     };
     Ok(())
 }
 
-fn login_velocity(enc: &mut PacketEncoder, username: String, login: &mut LoginState) -> anyhow::Result<()> {
-    let message_id: i32 = 0; // TODO: make this random?
-    enc.append_packet(&CLoginPluginRequest::new(
-        VarInt(message_id),
-        "velocity:player_info",
-        &[VELOCITY_MIN_SUPPORTED_VERSION]
-    ))?;
+/// Handles Velocity's modern forwarding answer, verifying the signature and
+/// taking the player's address and profile from it.
+fn velocity_response(ctx: &mut LoginQueryContext, data: Option<&[u8]>) -> anyhow::Result<()> {
+    let data = data.context("missing plugin response data")?;
+
+    ensure!(data.len() >= 32, "invalid plugin response data length");
+    let (signature, data_without_signature) = data.split_at(32);
+
+    let ConnectionMode::Velocity { secret } = &ctx.host.connection_mode else { bail!("invalid state"); };
+
+    // Verify signature
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    Mac::update(&mut mac, data_without_signature);
+    mac.verify_slice(&signature)?;
+
+    let mut data_without_signature = ByteBuffer::new(BytesMut::from(data_without_signature));
+    
+    // Check Velocity version
+    let version = data_without_signature.get_var_int()
+        .context("failed to decode velocity version")?
+        .0;
+    ensure!(
+        (VELOCITY_MODERN_FORWARDING_DEFAULT..=VELOCITY_MAX_SUPPORTED_VERSION).contains(&version),
+        "unsupported velocity forwarding version {version}",
+    );
+    
+    // Get client address
+    let remote_addr: IpAddr = data_without_signature.get_string()?.parse()?;
+    
+    // Get UUID
+    let uuid = data_without_signature.get_uuid()?;
+    
+    // Get username and validate
+    let name = data_without_signature.get_string()?;
+    ensure!(
+        ctx.username == name,
+        "mismatched usernames"
+    );
+
+    // Read game profile properties
+    let properties = data_without_signature.get_list(|data| {
+        let name = data.get_string()?;
+        let value = data.get_string()?;
+        let signature = data.get_option(|data| {
+            data.get_string()
+        })?;
+
+        Ok(ProfileProperty {
+            name,
+            value,
+            signature,
+        })
+    }).context("decoding velocity game profile properties")?;
+
+    // Only the key versions carry the player's chat signing key,
+    // lazy session clients send it later in the play state.
+    if version == VELOCITY_MODERN_FORWARDING_WITH_KEY || version == VELOCITY_MODERN_FORWARDING_WITH_KEY_V2 {
+        let expires_at = data_without_signature.get_i64()?;
+        let key = get_byte_array(&mut data_without_signature)?;
+        let signature = get_byte_array(&mut data_without_signature)?;
+        let holder = if version >= VELOCITY_MODERN_FORWARDING_WITH_KEY_V2 {
+            data_without_signature.get_option(|data| data.get_uuid())?
+        } else {
+            None
+        };
+
+        ctx.entity.set(PlayerPublicKey {
+            expires_at,
+            key,
+            signature,
+            holder,
+        });
+    }
 
-    *login = LoginState::VelocityResponse { message_id, username };
+    ctx.entity.set(RemoteAddress(remote_addr));
+    *ctx.profile = Some(LoginProfile { uuid, properties, address: Some(remote_addr) });
     Ok(())
 }
 
-fn offline_uuid(username: &str) -> uuid::Uuid {
-    uuid::Uuid::from_slice(&md5::compute(username)[..16]).expect("failed to create offline uuid")
+fn get_byte_array(data: &mut ByteBuffer) -> anyhow::Result<Vec<u8>> {
+    let len = data.get_var_int()?.0;
+    ensure!(len >= 0, "negative byte array length");
+    Ok(data.copy_to_bytes(len as usize)?.to_vec())
+}
+
+/// 3 to 16 characters of `[A-Za-z0-9_]`, like vanilla.
+fn valid_username(username: &str) -> bool {
+    (3..=16).contains(&username.len())
+        && username.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// The vanilla offline UUID, a version 3 UUID of `OfflinePlayer:<name>`.
+/// `legacy` is the raw MD5 of the bare name this server used before.
+fn offline_uuid(username: &str, legacy: bool) -> uuid::Uuid {
+    if legacy {
+        return uuid::Uuid::from_bytes(md5::compute(username).0);
+    }
+    uuid::Builder::from_md5_bytes(md5::compute(format!("OfflinePlayer:{username}")).0).into_uuid()
 }
 
 fn to_bytes<const N: usize>(slice: &[u8]) -> Option<&[u8; N]> {
@@ -4,11 +4,11 @@ use handshake::handshake_handler;
 use login::login_handler;
 use status::status_handler;
 
-use crate::{components::{client::{ClientPacketQueue, CurrentState, PacketDecoder, PacketEncoder}, resources::{KeyPair, ServerConfig, ServerStorage}}, error::PacketIoError};
+use crate::{components::{client::{ClientPacketQueue, CurrentState, Hostname, PacketDecoder, PacketEncoder}, resources::{KeyPair, ServerConfig, ServerStorage, VirtualHost}}, error::PacketIoError};
 
 mod handshake;
-mod status;
-mod login;
+pub mod status;
+pub mod login;
 mod config;
 pub mod play;
 
@@ -22,15 +22,30 @@ pub fn packet_handler(
     storage: &ServerStorage,
     key_pair: &KeyPair,
 ) -> Result<(), PacketIoError> {
+    // components set while handling the queue only show up once the system
+    // merges, so what the handshake resolved is carried along here
+    let mut host = virtual_host(e, config);
+    let mut forwarded = None;
     for packet in queue.iter().cloned() {
         let _guard = tracing::trace_span!("handle_packet", id = packet.id.0, state = state.to_string()).entered();
         match state {
-            CurrentState::HandShake => handshake_handler(packet, enc, state),
-            CurrentState::Status => status_handler(packet, enc, config, storage),
-            CurrentState::Login(_) => login_handler(packet, enc, dec, state, key_pair, &config.connection_mode, e).map_err(|err| err.into()),
+            CurrentState::HandShake => handshake_handler(e, packet, enc, state, config).map(|handshake| {
+                host = handshake.host;
+                forwarded = handshake.forwarded;
+            }),
+            CurrentState::Status => status_handler(packet, enc, host, config, storage),
+            CurrentState::Login(_) => login_handler(packet, enc, dec, state, key_pair, host, &mut forwarded, config, storage, e).map_err(|err| err.into()),
             CurrentState::Config(_) => config_handler(e, packet, enc, state),
             _ => return Err(PacketIoError::BadPacket("not yet implemented")),
         }?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The virtual host the client connected through, the default one before the
+/// handshake. Set by the handshake, so only visible from the next tick on.
+pub fn virtual_host<'a>(e: EntityView, config: &'a ServerConfig) -> &'a VirtualHost {
+    e.try_get::<&Hostname>(|hostname| config.host(&hostname.0))
+        .flatten()
+        .unwrap_or(&config.default_host)
+}
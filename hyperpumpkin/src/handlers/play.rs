@@ -1,13 +1,25 @@
+use anyhow::Context;
+use flecs_ecs::core::EntityView;
 use pumpkin_core::GameMode;
-use pumpkin_protocol::{bytebuf::packet_id::Packet, client::play::{CCenterChunk, CGameEvent, CLogin, CPlayerAbilities, CPlayerInfoUpdate, CSyncPlayerPosition, GameEvent}, server::play::{SPlayerPosition, SPlayerPositionRotation}, RawPacket};
+use pumpkin_protocol::{bytebuf::packet_id::Packet, client::play::{CCenterChunk, CGameEvent, CLogin, CPlayerAbilities, CPlayerInfoUpdate, CSyncPlayerPosition, GameEvent, Player, PlayerAction}, server::play::{SPlayerPosition, SPlayerPositionRotation}, Property, RawPacket};
 
-use crate::{components::client::PacketEncoder, error::PacketIoError};
+use crate::{components::{client::PacketEncoder, player::{ProfileProperties, SpawnWorld, Username, Uuid}}, config::DEFAULT_WORLD, error::PacketIoError};
+
+pub fn on_play(e: EntityView, enc: &mut PacketEncoder) -> anyhow::Result<()> {
+    // the profile is set on login success, which only merges at the end of the
+    // tick, so a client batching its way here within the same tick is turned away
+    let (uuid, name, properties) = e.try_get::<(&Uuid, &Username, &ProfileProperties)>(|(uuid, name, properties)| {
+        let properties: Vec<Property> = properties.0.iter().cloned().map(Property::from).collect();
+        (uuid.0, name.0.clone(), properties)
+    }).context("entered play before the login finished")?;
+
+    let world = e.try_get::<&SpawnWorld>(|world| world.0.clone())
+        .unwrap_or_else(|| DEFAULT_WORLD.to_string());
 
-pub fn on_play(enc: &mut PacketEncoder) -> anyhow::Result<()> {
     enc.append_packet(&CLogin::new(
         0.into(),
         false,
-        &["minecraft:overworld"],
+        &[world.as_str()],
         10.into(),
         16.into(),
         16.into(),
@@ -15,7 +27,7 @@ pub fn on_play(enc: &mut PacketEncoder) -> anyhow::Result<()> {
         false,
         false,
         0.into(),
-        "minecraft:overworld",
+        &world,
         0.into(),
         GameMode::Creative as u8,
         GameMode::Creative as i8,
@@ -42,9 +54,19 @@ pub fn on_play(enc: &mut PacketEncoder) -> anyhow::Result<()> {
         0.into(),
     ))?;
 
+    // the player's own tab list entry, properties carry the skin
     enc.append_packet(&CPlayerInfoUpdate::new(
         0x01 | 0x08,
-        &[],
+        &[Player {
+            uuid,
+            actions: vec![
+                PlayerAction::AddPlayer {
+                    name: &name,
+                    properties: &properties,
+                },
+                PlayerAction::UpdateListed(true),
+            ],
+        }],
     ))?;
 
     enc.append_packet(&CGameEvent::new(GameEvent::StartWaitingChunks, 0.0))?;
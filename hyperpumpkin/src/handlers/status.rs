@@ -1,11 +1,15 @@
 use pumpkin_protocol::{bytebuf::packet_id::Packet, client::status::{CPingResponse, CStatusResponse}, server::status::{SStatusPingRequest, SStatusRequest}, PacketError, RawPacket, ServerPacket};
 use serde_json::json;
 
-use crate::{components::{client::PacketEncoder, resources::{ServerConfig, ServerStorage}}, error::PacketIoError};
+use crate::{components::{client::{LegacyPing, PacketEncoder}, resources::{ServerConfig, ServerStorage, VirtualHost}}, error::PacketIoError};
+
+/// Players listed when hovering the player count, like vanilla.
+const SAMPLE_SIZE: usize = 12;
 
 pub fn status_handler(
     mut packet: RawPacket,
     enc: &mut PacketEncoder,
+    host: &VirtualHost,
     config: &ServerConfig,
     storage: &ServerStorage
 ) -> Result<(), PacketIoError> {
@@ -19,12 +23,13 @@ pub fn status_handler(
                 "players": {
                     "max": config.max_players,
                     "online": storage.online_players,
-                    "sample": [],
-                },
-                "description": {
-                    "text": config.description
+                    "sample": storage.sample.iter()
+                        .take(SAMPLE_SIZE)
+                        .map(|(id, name)| json!({ "name": name, "id": id.to_string() }))
+                        .collect::<Vec<_>>(),
                 },
-                "favicon": config.favicon,
+                "description": host.description,
+                "favicon": host.favicon,
                 "enforcesSecureChat": false,
             }).to_string();
 
@@ -39,4 +44,39 @@ pub fn status_handler(
         _ => return Err(PacketError::DecodeID.into()),
     };
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Protocol reported to legacy clients, any unknown number marks the server as incompatible.
+const LEGACY_PROTOCOL: i32 = 127;
+
+/// Kick packet answering a pre-netty server list ping, a UTF-16 string with
+/// the status fields. Legacy pings carry no hostname, so the default host is used.
+pub fn legacy_status_response(ping: LegacyPing, config: &ServerConfig, storage: &ServerStorage) -> Vec<u8> {
+    let response = if ping.with_version {
+        format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            LEGACY_PROTOCOL,
+            pumpkin_protocol::CURRENT_MC_VERSION,
+            config.default_host.description.to_legacy_lossy(),
+            storage.online_players,
+            config.max_players,
+        )
+    } else {
+        // fields are separated by § here, so the MOTD can't contain any
+        format!(
+            "{}§{}§{}",
+            config.default_host.description.to_legacy_lossy().replace('§', ""),
+            storage.online_players,
+            config.max_players,
+        )
+    };
+
+    let chars: Vec<u16> = response.encode_utf16().take(u16::MAX as usize).collect();
+    let mut bytes = Vec::with_capacity(3 + chars.len() * 2);
+    bytes.push(0xFF);
+    bytes.extend_from_slice(&(chars.len() as u16).to_be_bytes());
+    for char in chars {
+        bytes.extend_from_slice(&char.to_be_bytes());
+    }
+    bytes
+}
@@ -1,18 +1,22 @@
-use base64::{engine::general_purpose, Engine};
-use components::{client::{ClientConnection, ClientPacketQueue, CurrentState, PacketDecoder, PacketEncoder, RemoteAddress, SlabId}, player::{ClientBrand, GameMode, Play, PreviousGameMode, ProtocolId, Username, Uuid}, resources::{ConnectionMode, ExitSignal, KeyPair, ServerConfig, ServerStorage}};
+use config::Settings;
+use error::PacketIoError;
+use components::{client::{ClientConnection, ClientPacketQueue, CurrentState, Disconnect, Hostname, LegacyPing, OutgoingBuffer, PacketDecoder, PacketEncoder, RemoteAddress, ConnectionId, TrustedProxy}, player::{ClientBrand, GameMode, HiddenFromStatus, Play, PreviousGameMode, ProtocolId, SpawnWorld, Username, Uuid}, resources::{ExitSignal, KeyPair, ServerConfig, ServerStorage}};
 use flecs_ecs::prelude::*;
-use handlers::{packet_handler, play::play_handler};
-use modules::{KeepAliveModule, NetworkModule};
-use rsa::{pkcs8::EncodePublicKey, rand_core::OsRng, RsaPrivateKey};
+use handlers::{packet_handler, play::play_handler, status::legacy_status_response};
+use modules::{AccessModule, AuthModule, LoginPluginModule, CommandModule, ConfigModule, ConfigWatcher, ConsoleModule, ConsoleWriter, KeyModule, KeepAliveModule, NetworkModule, QueryModule, RconModule, ShutdownModule, TimeoutModule};
 use tracing::Level;
+use valence_text::Text;
 use tracing_subscriber::{fmt::writer::MakeWriterExt, layer::SubscriberExt, util::SubscriberInitExt};
 use std::{
     io,
     sync::atomic::Ordering,
+    time::Instant,
 };
 
 mod net;
+pub mod auth;
 pub mod components;
+pub mod config;
 mod error;
 mod handlers;
 pub mod modules;
@@ -27,8 +31,19 @@ fn main() {
     tracing_subscriber::registry()
         .with(chrome)
         .with(tracing_subscriber::fmt::layer()
-            .map_writer(|w| w.with_max_level(Level::DEBUG)))
+            .with_writer(ConsoleWriter.with_max_level(Level::DEBUG)))
         .init();    
+
+    let config_path = Settings::path();
+    let loaded = Settings::load(&config_path)
+        .and_then(|settings| Ok((settings.server_config()?, settings)));
+    let (server_config, settings) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            tracing::error!("Failed to load config: {}", err);
+            std::process::exit(1);
+        },
+    };
     
     let world = World::new();
     let world = Box::new(world);
@@ -36,19 +51,32 @@ fn main() {
     
     // spawn new players into the world
 
+    world.set(settings.network_settings());
+
     world.import::<NetworkModule>();
     world.import::<KeepAliveModule>();
+    world.import::<AuthModule>();
+    world.import::<LoginPluginModule>();
+    world.import::<TimeoutModule>();
+    world.set(settings.keepalive_settings());
+    world.set(settings.phase_timeouts());
 
     world.component::<PacketEncoder>();
     world.component::<PacketDecoder>();
     world.component::<ClientPacketQueue>();
-    world.component::<SlabId>();
+    world.component::<ConnectionId>();
     world.component::<ClientConnection>();
     world.component::<RemoteAddress>();
+    world.component::<TrustedProxy>();
+    world.component::<Disconnect>();
+    world.component::<LegacyPing>();
+    world.component::<Hostname>();
     world.component::<CurrentState>();
 
     world.component::<Play>();
     world.component::<Uuid>();
+    world.component::<HiddenFromStatus>();
+    world.component::<SpawnWorld>();
     world.component::<Username>();
     world.component::<ClientBrand>();
     world.component::<ProtocolId>();
@@ -65,43 +93,70 @@ fn main() {
     })
     .expect("Error setting Ctrl-C handler");
 
-    world.system_named::<&ExitSignal>("ctrlc_exit")
-        .term_at(0).singleton()
-        .each(|signal| {
-            if signal.load(Ordering::SeqCst) {
-                tracing::info!("Ctrl-C detected, quitting.");
-                world.quit();
-            }
-        });
-
-    tracing::debug!("generating RSA key");
-    let private = RsaPrivateKey::new(&mut OsRng, 2048)
-        .expect("generate private key");
+    world.import::<ShutdownModule>();
+    world.set(settings.shutdown_settings());
 
-    tracing::debug!("finished generating RSA key");
+    world.set(server_config);
+    world.set(ConfigWatcher::new(config_path, settings.clone()));
+    world.import::<ConfigModule>();
 
-    let public_bytes = private.to_public_key().to_public_key_der().expect("public key to bytes");
+    world.set(ServerStorage::default());
 
-    world.set(KeyPair {
-        private,
-        public_bytes
-    });
+    if settings.query.enabled {
+        world.set(settings.query_settings());
+        world.import::<QueryModule>();
+    }
 
-    let bytes = include_bytes!("../../icon.png");
-    let base64 = general_purpose::STANDARD.encode(bytes);
+    world.import::<CommandModule>();
+    world.import::<ConsoleModule>();
+    world.set(settings.key_settings());
+    world.import::<KeyModule>();
+    world.set(settings.access_settings());
+    world.import::<AccessModule>();
+    if settings.rcon.enabled {
+        world.set(settings.rcon_settings());
+        world.import::<RconModule>();
+    }
 
-    world.set(ServerConfig {
-        max_players: 10,
-        description: "Hello, World!".to_string(),
-        favicon: format!("data:image/png;base64,{}", base64),
-        // connection_mode: ConnectionMode::Velocity { secret: std::sync::atomic::Arc::from(include_str!("../../forwarding.secret")) }
-        connection_mode: ConnectionMode::Offline,
-    });
-
-    world.set(ServerStorage {
-        connections: 0,
-        online_players: 0,
-    });
+    world.observer::<flecs::OnAdd, &mut ServerStorage>()
+        .with::<ClientConnection>()
+        .term_at(0).singleton()
+        .each(|storage| storage.connections += 1);
+    world.observer::<flecs::OnRemove, &mut ServerStorage>()
+        .with::<ClientConnection>()
+        .term_at(0).singleton()
+        .each(|storage| storage.connections -= 1);
+    world.observer::<flecs::OnAdd, &mut ServerStorage>()
+        .with::<Play>()
+        .term_at(0).singleton()
+        .each(|storage| storage.online_players += 1);
+    world.observer::<flecs::OnRemove, &mut ServerStorage>()
+        .with::<Play>()
+        .term_at(0).singleton()
+        .each(|storage| storage.online_players -= 1);
+    world.observer::<flecs::OnAdd, (&Username, &Uuid, &mut ServerStorage)>()
+        .with::<Play>()
+        .without::<HiddenFromStatus>()
+        .term_at(2).singleton()
+        .each(|(username, uuid, storage)| {
+            storage.sample.insert(uuid.0, username.0.clone());
+        });
+    world.observer::<flecs::OnRemove, (&Uuid, &mut ServerStorage)>()
+        .with::<Play>()
+        .term_at(1).singleton()
+        .each(|(uuid, storage)| {
+            storage.sample.remove(&uuid.0);
+        });
+    // players are claimed in `finish_login`, a session replaced by a
+    // duplicate login leaves the newer one in place
+    world.observer::<flecs::OnRemove, (&Uuid, &mut ServerStorage)>()
+        .term_at(1).singleton()
+        .each_entity(|e, (uuid, storage)| {
+            let players = storage.players.get_mut();
+            if players.get(&uuid.0) == Some(&e.id()) {
+                players.remove(&uuid.0);
+            }
+        });
 
     world.system_named::<(
         &ClientPacketQueue,
@@ -116,6 +171,7 @@ fn main() {
         .term_at(4).singleton()
         .term_at(5).singleton()
         .term_at(6).singleton()
+        .without::<Disconnect>()
         .each_entity(|e, (
             queue,
             enc,
@@ -127,23 +183,43 @@ fn main() {
         )| {
             match packet_handler(e, queue, enc, dec, state, config, storage, key_pair) {
                 Ok(_) => {},
+                // already logged where the connection was refused
+                Err(err @ PacketIoError::Refused(_)) => {
+                    e.set(Disconnect::new(err.reason()));
+                },
                 Err(err) => {
                     tracing::warn!("bad packet: {}", err);
-                    e.destruct();
+                    e.set(Disconnect::new(err.reason()));
                 },
             }
         });
     
+    world.system_named::<(&LegacyPing, &mut OutgoingBuffer, &ServerConfig, &ServerStorage)>("legacy_ping")
+        .multi_threaded()
+        .term_at(2).singleton()
+        .term_at(3).singleton()
+        .without::<Disconnect>()
+        .each_entity(|e, (ping, outgoing, config, storage)| {
+            outgoing.extend_from_slice(&legacy_status_response(*ping, config, storage));
+            // the response is the whole exchange, close once it's flushed
+            e.set(Disconnect {
+                reason: Text::text("Legacy ping"),
+                sent: Some(Instant::now()),
+            });
+        });
+
     world.system_named::<&ClientPacketQueue>("play")
         .multi_threaded()
         .with::<Play>()
+        .without::<Disconnect>()
         .each_entity(|e, queue| {
             for packet in queue.iter().cloned() {
                 match play_handler(packet) {
                     Ok(_) => {},
                     Err(err) => {
                         tracing::warn!("play error: {}", err);
-                        e.destruct();
+                        e.set(Disconnect::new(err.reason()));
+                        break;
                     },
                 }
             }
@@ -165,8 +241,8 @@ fn main() {
 
     let mut app = world.app();
 
-    app.set_threads(4)
-        .set_target_fps(20.0)
+    app.set_threads(settings.server.threads as i32)
+        .set_target_fps(settings.server.tps)
         .enable_stats(true);
         
     
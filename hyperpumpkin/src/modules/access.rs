@@ -0,0 +1,484 @@
+use std::{fs, io::Write, net::IpAddr, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use flecs_ecs::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{components::{client::{Disconnect, RemoteAddress}, player::{Username, Uuid}}, config::Settings};
+
+use super::{find_player, player_names, CommandContext, Commands};
+
+const FOREVER: &str = "forever";
+const DEFAULT_BAN_REASON: &str = "Banned by an operator.";
+
+/// `access` section of the config.
+#[derive(Component, Clone)]
+pub struct AccessSettings {
+    /// Only players on the whitelist may join.
+    pub whitelist: bool,
+    pub whitelist_file: PathBuf,
+    pub banned_players_file: PathBuf,
+    pub banned_ips_file: PathBuf,
+}
+
+impl Default for AccessSettings {
+    fn default() -> Self {
+        Settings::default().access_settings()
+    }
+}
+
+/// An entry of `whitelist.json`. Entries without a UUID match by name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WhitelistEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<uuid::Uuid>,
+    pub name: String,
+}
+
+impl WhitelistEntry {
+    fn matches(&self, uuid: &uuid::Uuid, username: &str) -> bool {
+        match self.uuid {
+            Some(entry) => entry == *uuid,
+            None => self.name.eq_ignore_ascii_case(username),
+        }
+    }
+}
+
+/// An entry of `banned-players.json`, matching the UUID or the name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerBan {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<uuid::Uuid>,
+    pub name: String,
+    #[serde(flatten)]
+    pub details: BanDetails,
+}
+
+impl PlayerBan {
+    fn matches(&self, uuid: &uuid::Uuid, username: &str) -> bool {
+        self.uuid == Some(*uuid) || self.name.eq_ignore_ascii_case(username)
+    }
+}
+
+/// An entry of `banned-ips.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpBan {
+    pub ip: IpAddr,
+    #[serde(flatten)]
+    pub details: BanDetails,
+}
+
+/// Dates are formatted like vanilla's `yyyy-MM-dd HH:mm:ss Z`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BanDetails {
+    pub created: String,
+    pub source: String,
+    /// `forever` or a date, bans with a date vanilla can't parse never expire either.
+    pub expires: String,
+    pub reason: String,
+}
+
+impl Default for BanDetails {
+    fn default() -> Self {
+        Self {
+            created: format_date(unix_now()),
+            source: "(Unknown)".to_string(),
+            expires: FOREVER.to_string(),
+            reason: DEFAULT_BAN_REASON.to_string(),
+        }
+    }
+}
+
+impl BanDetails {
+    fn expires_at(&self) -> Option<i64> {
+        if self.expires == FOREVER { None } else { parse_date(&self.expires) }
+    }
+
+    fn active(&self, now: i64) -> bool {
+        self.expires_at().map_or(true, |expires| expires > now)
+    }
+
+    /// The disconnect screen, with the reason and when the ban ends.
+    fn message(&self, header: &str) -> String {
+        let mut message = format!("{header}\nReason: {}", self.reason);
+        if let Some(expires) = self.expires_at() {
+            message.push_str(&format!("\nYour ban will be removed on {}", format_date(expires)));
+        }
+        message
+    }
+}
+
+/// The whitelist and ban lists, saved to their files by the commands changing them.
+#[derive(Component, Default)]
+pub struct AccessLists {
+    pub whitelist: Vec<WhitelistEntry>,
+    pub banned_players: Vec<PlayerBan>,
+    pub banned_ips: Vec<IpBan>,
+}
+
+impl AccessLists {
+    pub fn load(settings: &AccessSettings) -> anyhow::Result<Self> {
+        Ok(Self {
+            whitelist: load(&settings.whitelist_file)?,
+            banned_players: load(&settings.banned_players_file)?,
+            banned_ips: load(&settings.banned_ips_file)?,
+        })
+    }
+
+    /// The disconnect reason if the player may not join, checked in vanilla's
+    /// order: player bans, the whitelist, then address bans.
+    pub fn refusal(&self, whitelist: bool, uuid: &uuid::Uuid, username: &str, address: Option<IpAddr>) -> Option<String> {
+        let now = unix_now();
+        if let Some(ban) = self.banned_players.iter().find(|ban| ban.matches(uuid, username) && ban.details.active(now)) {
+            return Some(ban.details.message("You are banned from this server."));
+        }
+        if whitelist && !self.whitelist.iter().any(|entry| entry.matches(uuid, username)) {
+            return Some("You are not white-listed on this server!".to_string());
+        }
+        let address = address?.to_canonical();
+        self.banned_ips.iter()
+            .find(|ban| ban.ip.to_canonical() == address && ban.details.active(now))
+            .map(|ban| ban.details.message("Your IP address is banned from this server."))
+    }
+}
+
+/// Checks a login against the [`AccessLists`], allowing everyone if the
+/// [`AccessModule`] isn't imported.
+pub fn login_refusal(world: WorldRef, uuid: &uuid::Uuid, username: &str, address: Option<IpAddr>) -> Option<String> {
+    let whitelist = world.get::<Option<&AccessSettings>>(|settings| settings.is_some_and(|settings| settings.whitelist));
+    world.get::<Option<&AccessLists>>(|lists| lists?.refusal(whitelist, uuid, username, address))
+}
+
+/// Loads the vanilla `whitelist.json`, `banned-players.json` and
+/// `banned-ips.json`, which logins are checked against before `CLoginSuccess`,
+/// and registers the `whitelist`, `ban`, `ban-ip`, `pardon`, `pardon-ip` and
+/// `banlist` commands if the [`CommandModule`](super::CommandModule) is imported.
+#[derive(Component)]
+pub struct AccessModule;
+
+impl Module for AccessModule {
+    fn module(world: &World) {
+        let settings = world.get::<Option<&AccessSettings>>(|settings| settings.cloned().unwrap_or_default());
+        match AccessLists::load(&settings) {
+            Ok(lists) => {
+                world.set(lists);
+            },
+            Err(err) => {
+                // starting without the ban list would let everyone in and overwrite it on the next ban
+                tracing::error!("Failed to load the access lists: {:#}", err);
+                std::process::exit(1);
+            },
+        }
+        world.set(settings);
+
+        world.get::<Option<&mut Commands>>(|commands| {
+            let Some(commands) = commands else { return; };
+            register_commands(commands);
+        });
+    }
+}
+
+fn register_commands(commands: &mut Commands) {
+    commands.register("whitelist", "<on|off|add|remove|list|reload> [player]", "Manages the whitelist", |ctx, args| {
+        let (action, args) = args.split_first().context("missing action")?;
+        match *action {
+            "on" | "off" => {
+                let enabled = *action == "on";
+                ctx.world.get::<&mut AccessSettings>(|settings| settings.whitelist = enabled);
+                ctx.reply(format!("Turned the whitelist {action}, set `access.whitelist` to keep it after a restart"));
+            },
+            "add" => {
+                let name = args.first().context("missing player")?;
+                let (uuid, name) = resolve_player(ctx.world, name);
+                let whitelist_file = ctx.world.get::<&AccessSettings>(|settings| settings.whitelist_file.clone());
+                ctx.world.get::<&mut AccessLists>(|lists| {
+                    let listed = lists.whitelist.iter().any(|entry| {
+                        entry.name.eq_ignore_ascii_case(&name) || (entry.uuid.is_some() && entry.uuid == uuid)
+                    });
+                    anyhow::ensure!(!listed, "{name} is already whitelisted");
+                    lists.whitelist.push(WhitelistEntry { uuid, name: name.clone() });
+                    save(&whitelist_file, &lists.whitelist)
+                })?;
+                ctx.reply(format!("Added {name} to the whitelist"));
+            },
+            "remove" => {
+                let name = args.first().context("missing player")?;
+                let whitelist_file = ctx.world.get::<&AccessSettings>(|settings| settings.whitelist_file.clone());
+                ctx.world.get::<&mut AccessLists>(|lists| {
+                    let before = lists.whitelist.len();
+                    lists.whitelist.retain(|entry| !names_player(entry.uuid, &entry.name, name));
+                    anyhow::ensure!(lists.whitelist.len() != before, "{name} is not whitelisted");
+                    save(&whitelist_file, &lists.whitelist)
+                })?;
+                ctx.reply(format!("Removed {name} from the whitelist"));
+            },
+            "list" => {
+                let names: Vec<String> = ctx.world.get::<&AccessLists>(|lists| {
+                    lists.whitelist.iter().map(|entry| entry.name.clone()).collect()
+                });
+                ctx.reply(format!("There are {} whitelisted players: {}", names.len(), names.join(", ")));
+            },
+            "reload" => {
+                let whitelist_file = ctx.world.get::<&AccessSettings>(|settings| settings.whitelist_file.clone());
+                let whitelist = load(&whitelist_file)?;
+                ctx.world.get::<&mut AccessLists>(|lists| lists.whitelist = whitelist);
+                ctx.reply("Reloaded the whitelist");
+            },
+            _ => anyhow::bail!("unknown action {action}"),
+        }
+        Ok(())
+    }).completer(|world, args| match args {
+        [] => ["on", "off", "add", "remove", "list", "reload"].map(String::from).to_vec(),
+        ["add"] => player_names(world),
+        ["remove"] => world.get::<&AccessLists>(|lists| lists.whitelist.iter().map(|entry| entry.name.clone()).collect()),
+        _ => Vec::new(),
+    });
+
+    commands.register("ban", "<player> [reason]", "Bans a player by name and UUID", |ctx, args| {
+        let (name, reason) = args.split_first().context("missing player")?;
+        let (uuid, name) = resolve_player(ctx.world, name);
+        let ban = PlayerBan {
+            uuid,
+            name: name.clone(),
+            details: new_ban(ctx, reason),
+        };
+        let message = ban.details.message("You are banned from this server.");
+
+        let banned_players_file = ctx.world.get::<&AccessSettings>(|settings| settings.banned_players_file.clone());
+        ctx.world.get::<&mut AccessLists>(|lists| {
+            lists.banned_players.retain(|existing| !names_player(existing.uuid, &existing.name, &name));
+            lists.banned_players.push(ban);
+            save(&banned_players_file, &lists.banned_players)
+        })?;
+
+        if let Some(player) = find_player(ctx.world, &name) {
+            ctx.world.entity_from_id(player).set(Disconnect::new(message.as_str()));
+        }
+        ctx.reply(format!("Banned {name}"));
+        Ok(())
+    }).completer(|world, args| if args.is_empty() { player_names(world) } else { Vec::new() });
+
+    commands.register("ban-ip", "<address|player> [reason]", "Bans an IP address and kicks everyone connected from it", |ctx, args| {
+        let (target, reason) = args.split_first().context("missing address or player")?;
+        let ip = match target.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let player = find_player(ctx.world, target)
+                    .with_context(|| format!("{target} is not an address or an online player"))?;
+                ctx.world.entity_from_id(player).try_get::<&RemoteAddress>(|addr| addr.0)
+                    .with_context(|| format!("the address of {target} is unknown"))?
+            },
+        }.to_canonical();
+        let ban = IpBan { ip, details: new_ban(ctx, reason) };
+        let message = ban.details.message("Your IP address is banned from this server.");
+
+        let banned_ips_file = ctx.world.get::<&AccessSettings>(|settings| settings.banned_ips_file.clone());
+        ctx.world.get::<&mut AccessLists>(|lists| {
+            lists.banned_ips.retain(|existing| existing.ip.to_canonical() != ip);
+            lists.banned_ips.push(ban);
+            save(&banned_ips_file, &lists.banned_ips)
+        })?;
+
+        let mut kicked = 0;
+        ctx.world.each_entity::<&RemoteAddress>(|e, addr| {
+            if addr.0.to_canonical() == ip && !e.has::<Disconnect>() {
+                e.set(Disconnect::new(message.as_str()));
+                kicked += 1;
+            }
+        });
+        ctx.reply(format!("Banned {ip}, kicked {kicked} connections"));
+        Ok(())
+    }).completer(|world, args| if args.is_empty() { player_names(world) } else { Vec::new() });
+
+    commands.register("pardon", "<player>", "Removes a player's ban", |ctx, args| {
+        let name = args.first().context("missing player")?;
+        let banned_players_file = ctx.world.get::<&AccessSettings>(|settings| settings.banned_players_file.clone());
+        ctx.world.get::<&mut AccessLists>(|lists| {
+            let before = lists.banned_players.len();
+            lists.banned_players.retain(|ban| !names_player(ban.uuid, &ban.name, name));
+            anyhow::ensure!(lists.banned_players.len() != before, "{name} is not banned");
+            save(&banned_players_file, &lists.banned_players)
+        })?;
+        ctx.reply(format!("Unbanned {name}"));
+        Ok(())
+    }).completer(|world, args| match args {
+        [] => world.get::<&AccessLists>(|lists| lists.banned_players.iter().map(|ban| ban.name.clone()).collect()),
+        _ => Vec::new(),
+    });
+
+    commands.register("pardon-ip", "<address>", "Removes an IP address ban", |ctx, args| {
+        let ip = args.first().context("missing address")?
+            .parse::<IpAddr>()
+            .context("invalid address")?
+            .to_canonical();
+        let banned_ips_file = ctx.world.get::<&AccessSettings>(|settings| settings.banned_ips_file.clone());
+        ctx.world.get::<&mut AccessLists>(|lists| {
+            let before = lists.banned_ips.len();
+            lists.banned_ips.retain(|ban| ban.ip.to_canonical() != ip);
+            anyhow::ensure!(lists.banned_ips.len() != before, "{ip} is not banned");
+            save(&banned_ips_file, &lists.banned_ips)
+        })?;
+        ctx.reply(format!("Unbanned {ip}"));
+        Ok(())
+    }).completer(|world, args| match args {
+        [] => world.get::<&AccessLists>(|lists| lists.banned_ips.iter().map(|ban| ban.ip.to_string()).collect()),
+        _ => Vec::new(),
+    });
+
+    commands.register("banlist", "[players|ips]", "Lists the bans", |ctx, args| {
+        let kind = args.first().copied().unwrap_or("players");
+        let lines: Vec<String> = ctx.world.get::<&AccessLists>(|lists| match kind {
+            "players" => Ok(lists.banned_players.iter().map(|ban| describe_ban(&ban.name, &ban.details)).collect()),
+            "ips" => Ok(lists.banned_ips.iter().map(|ban| describe_ban(&ban.ip.to_string(), &ban.details)).collect()),
+            _ => Err(anyhow::anyhow!("unknown list {kind}")),
+        })?;
+        ctx.reply(format!("There are {} bans", lines.len()));
+        for line in lines {
+            ctx.reply(line);
+        }
+        Ok(())
+    }).completer(|_, args| if args.is_empty() { vec!["players".to_string(), "ips".to_string()] } else { Vec::new() });
+}
+
+/// The UUID and name of an online player, just the name as typed otherwise.
+fn resolve_player(world: WorldRef, name: &str) -> (Option<uuid::Uuid>, String) {
+    match find_player(world, name) {
+        Some(player) => world.entity_from_id(player).get::<(&Uuid, &Username)>(|(uuid, username)| (Some(uuid.0), username.0.clone())),
+        None => (None, name.to_string()),
+    }
+}
+
+/// Whether `target` is the entry's name or UUID.
+fn names_player(uuid: Option<uuid::Uuid>, name: &str, target: &str) -> bool {
+    name.eq_ignore_ascii_case(target) || uuid.is_some_and(|uuid| uuid.to_string().eq_ignore_ascii_case(target))
+}
+
+fn new_ban(ctx: &CommandContext, reason: &[&str]) -> BanDetails {
+    BanDetails {
+        source: ctx.source.to_string(),
+        reason: if reason.is_empty() { DEFAULT_BAN_REASON.to_string() } else { reason.join(" ") },
+        ..BanDetails::default()
+    }
+}
+
+fn describe_ban(target: &str, details: &BanDetails) -> String {
+    format!("{target} was banned by {} until {}: {}", details.source, details.expires, details.reason)
+}
+
+/// A missing file is an empty list.
+fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Replaces the file at `path` with `entries`.
+fn save<T: Serialize>(path: &Path, entries: &[T]) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(entries).context("failed to encode list")?;
+
+    // written next to the target and renamed, so a crash never leaves a truncated list
+    let tmp = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp)
+        .with_context(|| format!("failed to create {}", tmp.display()))?;
+    file.write_all(json.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("failed to replace {}", path.display()))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64)
+}
+
+/// Parses `yyyy-MM-dd HH:mm:ss Z`, e.g. `2024-02-29 13:45:10 +0100`, into unix seconds.
+fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.split_whitespace();
+    let (date, time, zone) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let numbers = |part: &str, separator: char| -> Option<[i64; 3]> {
+        let mut fields = part.split(separator).map(|field| field.parse::<i64>().ok());
+        let parsed = [fields.next()??, fields.next()??, fields.next()??];
+        fields.next().is_none().then_some(parsed)
+    };
+    let [year, month, day] = numbers(date, '-')?;
+    let [hour, minute, second] = numbers(time, ':')?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let sign = match zone.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let offset = zone.get(1..).filter(|offset| offset.len() == 4)?;
+    let offset_hours: i64 = offset[..2].parse().ok()?;
+    let offset_minutes: i64 = offset[2..].parse().ok()?;
+
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(local - sign * (offset_hours * 3600 + offset_minutes * 60))
+}
+
+/// Formats unix seconds as `yyyy-MM-dd HH:mm:ss +0000`.
+fn format_date(unix: i64) -> String {
+    let (year, month, day) = civil_from_days(unix.div_euclid(86400));
+    let seconds = unix.rem_euclid(86400);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} +0000",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_date, parse_date};
+
+    #[test]
+    fn parses_vanilla_dates() {
+        assert_eq!(parse_date("2024-02-29 13:45:10 +0100"), Some(1709210710));
+        assert_eq!(parse_date("1969-12-31 19:00:00 -0500"), Some(0));
+        assert_eq!(parse_date("forever"), None);
+        assert_eq!(parse_date("2024-13-01 00:00:00 +0000"), None);
+    }
+
+    #[test]
+    fn formats_dates_in_utc() {
+        assert_eq!(format_date(1709210710), "2024-02-29 12:45:10 +0000");
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 +0000");
+    }
+}
@@ -0,0 +1,67 @@
+use crossbeam::channel::TryRecvError;
+use flecs_ecs::prelude::*;
+
+use crate::{components::{client::{CurrentState, Disconnect, LoginState, PacketDecoder, PacketEncoder, PendingAuth}, resources::{ServerConfig, ServerStorage}}, error::PacketIoError, handlers::{login::begin_queries, virtual_host}, modules::LoginProfile};
+
+/// Completes online-mode logins once the session server answered.
+#[derive(Component)]
+pub struct AuthModule;
+
+impl Module for AuthModule {
+    fn module(world: &World) {
+        world.component::<PendingAuth>();
+
+        world.system_named::<(&PendingAuth, &mut PacketEncoder, &mut PacketDecoder, &mut CurrentState, &ServerConfig, &ServerStorage)>("finish_auth")
+            .multi_threaded()
+            .term_at(4).singleton()
+            .term_at(5).singleton()
+            .without::<Disconnect>()
+            .each_entity(|e, (pending, enc, dec, state, config, storage)| {
+                let result = match pending.0.try_recv() {
+                    Ok(result) => result,
+                    Err(TryRecvError::Empty) => return,
+                    Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("authentication thread exited")),
+                };
+                e.remove::<PendingAuth>();
+
+                let CurrentState::Login(login) = state else {
+                    tracing::warn!("Client {e} finished authentication in state {state}");
+                    e.set(Disconnect::new("Failed to log in"));
+                    return;
+                };
+                let LoginState::Authenticating { username } = login else {
+                    tracing::warn!("Client {e} finished authentication outside of the authenticating state");
+                    e.set(Disconnect::new("Failed to log in"));
+                    return;
+                };
+
+                let profile = match result {
+                    Ok(Some(profile)) => profile,
+                    Ok(None) => {
+                        tracing::info!("Session server rejected {username}");
+                        e.set(Disconnect::new("Failed to verify username!"));
+                        return;
+                    },
+                    Err(err) => {
+                        tracing::warn!("Failed to authenticate {username}: {}", err);
+                        e.set(Disconnect::new("Authentication servers are down. Please try again later, sorry!"));
+                        return;
+                    },
+                };
+
+                let username = std::mem::take(username);
+                let login_profile = LoginProfile {
+                    uuid: profile.id,
+                    properties: profile.properties,
+                    address: None,
+                };
+                if let Err(err) = begin_queries(e, enc, dec, virtual_host(e, config), config, storage, profile.name, login_profile, login) {
+                    let err = PacketIoError::from(err);
+                    if !matches!(err, PacketIoError::Refused(_)) {
+                        tracing::warn!("Failed to finish login for {username}: {}", err);
+                    }
+                    e.set(Disconnect::new(err.reason()));
+                }
+            });
+    }
+}
@@ -0,0 +1,364 @@
+use std::{collections::{BTreeMap, VecDeque}, fmt::Display, fs, net::SocketAddr, sync::{atomic::Ordering, Arc}, time::Instant};
+
+use anyhow::Context;
+use crossbeam::channel::{Receiver, Sender};
+use flecs_ecs::prelude::*;
+use pumpkin_core::text::TextComponent;
+use pumpkin_protocol::client::play::CSystemChatMessage;
+
+use crate::components::{client::{Disconnect, PacketEncoder}, player::{Play, Username}, resources::{ExitSignal, NetworkIo, ServerConfig, ServerStorage}};
+
+use super::ConfigWatcher;
+
+/// Where a command line came from.
+#[derive(Debug, Clone)]
+pub enum CommandSource {
+    Console,
+    Rcon(SocketAddr),
+}
+
+impl Display for CommandSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandSource::Console => write!(f, "console"),
+            CommandSource::Rcon(addr) => write!(f, "rcon {addr}"),
+        }
+    }
+}
+
+/// Passed to command handlers, collects the output sent back to the source.
+pub struct CommandContext<'a> {
+    pub world: WorldRef<'a>,
+    pub source: &'a CommandSource,
+    output: Vec<String>,
+}
+
+impl CommandContext<'_> {
+    pub fn reply(&mut self, line: impl Into<String>) {
+        self.output.push(line.into());
+    }
+}
+
+type CommandHandler = Arc<dyn Fn(&mut CommandContext, &[&str]) -> anyhow::Result<()> + Send + Sync>;
+type CommandCompleter = Arc<dyn Fn(WorldRef, &[&str]) -> Vec<String> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Command {
+    pub usage: &'static str,
+    pub description: &'static str,
+    handler: CommandHandler,
+    completer: Option<CommandCompleter>,
+}
+
+impl Command {
+    /// Suggests the argument being typed, given the ones before it.
+    pub fn completer(&mut self, completer: impl Fn(WorldRef, &[&str]) -> Vec<String> + Send + Sync + 'static) -> &mut Self {
+        self.completer = Some(Arc::new(completer));
+        self
+    }
+}
+
+/// Every command the dispatcher knows, by name.
+#[derive(Component, Default)]
+pub struct Commands {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl Commands {
+    /// `usage` lists the arguments, e.g. `<player> [reason]`.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        description: &'static str,
+        handler: impl Fn(&mut CommandContext, &[&str]) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> &mut Command {
+        self.commands.insert(name, Command {
+            usage,
+            description,
+            handler: Arc::new(handler),
+            completer: None,
+        });
+        self.commands.get_mut(name).expect("just inserted")
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Command)> {
+        self.commands.iter().map(|(name, command)| (*name, command))
+    }
+
+    /// Candidates for the last word of `line`: command names for the first
+    /// word, the command's completer for its arguments.
+    pub fn complete(&self, world: WorldRef, line: &str) -> Vec<String> {
+        let words: Vec<&str> = line.trim_start().split(' ').collect();
+        let Some((partial, before)) = words.split_last() else { return Vec::new(); };
+
+        let candidates = match before.split_first() {
+            None => self.commands.keys().map(|name| name.to_string()).collect(),
+            Some((name, args)) => match self.commands.get(name).and_then(|command| command.completer.as_ref()) {
+                Some(completer) => completer(world, args),
+                None => Vec::new(),
+            },
+        };
+        candidates.into_iter()
+            .filter(|candidate| candidate.to_lowercase().starts_with(&partial.to_lowercase()))
+            .collect()
+    }
+}
+
+/// A command line waiting for the next tick. The output is sent to `reply`,
+/// or logged if there is none.
+pub struct CommandRequest {
+    pub source: CommandSource,
+    pub line: String,
+    pub reply: Option<Sender<Vec<String>>>,
+}
+
+/// A partial command line to complete, answered with the candidates for its last word.
+pub struct CompletionRequest {
+    pub line: String,
+    pub reply: Sender<Vec<String>>,
+}
+
+/// Lets other threads, like the console and RCON, queue commands for the ECS.
+#[derive(Component, Clone)]
+pub struct CommandQueue {
+    sender: Sender<CommandRequest>,
+    receiver: Receiver<CommandRequest>,
+    completion_sender: Sender<CompletionRequest>,
+    completion_receiver: Receiver<CompletionRequest>,
+}
+
+impl CommandQueue {
+    pub fn sender(&self) -> Sender<CommandRequest> {
+        self.sender.clone()
+    }
+
+    pub fn completion_sender(&self) -> Sender<CompletionRequest> {
+        self.completion_sender.clone()
+    }
+}
+
+/// How many tick start times are kept to measure the tick rate.
+const TICK_SAMPLES: usize = 100;
+
+/// Start times of the most recent ticks, for `tps`.
+#[derive(Component, Default)]
+struct TickTimes(VecDeque<Instant>);
+
+impl TickTimes {
+    /// Ticks per second over the last `ticks` ticks, if that many were recorded.
+    fn tps(&self, ticks: usize) -> Option<f64> {
+        if ticks == 0 || self.0.len() <= ticks {
+            return None;
+        }
+        let last = self.0.back()?;
+        let first = self.0.get(self.0.len() - 1 - ticks)?;
+        Some(ticks as f64 / last.duration_since(*first).as_secs_f64())
+    }
+}
+
+/// Runs queued command lines once per tick against the [`Commands`] registry.
+#[derive(Component)]
+pub struct CommandModule;
+
+impl Module for CommandModule {
+    fn module(world: &World) {
+        world.component::<TickTimes>();
+
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let (completion_sender, completion_receiver) = crossbeam::channel::unbounded();
+        world.set(CommandQueue { sender, receiver, completion_sender, completion_receiver });
+        world.set(TickTimes::default());
+
+        let mut commands = Commands::default();
+        register_builtins(&mut commands);
+        world.set(commands);
+
+        world.system_named::<&mut TickTimes>("tick_times")
+            .term_at(0).singleton()
+            .each(|times| {
+                if times.0.len() > TICK_SAMPLES {
+                    times.0.pop_front();
+                }
+                times.0.push_back(Instant::now());
+            });
+
+        world.system_named::<()>("dispatch_commands")
+            .each_iter(|it, _, _| {
+                let world = it.world();
+                let completions: Vec<CompletionRequest> = world.get::<&CommandQueue>(|queue| queue.completion_receiver.try_iter().collect());
+                for request in completions {
+                    let candidates = world.get::<&Commands>(|commands| commands.complete(world, &request.line));
+                    let _ = request.reply.send(candidates);
+                }
+
+                let requests: Vec<CommandRequest> = world.get::<&CommandQueue>(|queue| queue.receiver.try_iter().collect());
+                for request in requests {
+                    let output = dispatch(world, &request.source, &request.line);
+                    match request.reply {
+                        Some(reply) => {
+                            let _ = reply.send(output);
+                        },
+                        None => {
+                            for line in output {
+                                tracing::info!("{}", line);
+                            }
+                        },
+                    }
+                }
+            });
+    }
+}
+
+/// Runs one command line and returns its output.
+pub fn dispatch(world: WorldRef, source: &CommandSource, line: &str) -> Vec<String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = args.split_first() else { return Vec::new(); };
+
+    // cloned out so the handler can access the registry itself
+    let Some(command) = world.get::<&Commands>(|commands| commands.get(name).cloned()) else {
+        return vec![format!("Unknown command `{name}`, try `help`")];
+    };
+
+    tracing::info!("{} issued command: {}", source, line);
+    let mut ctx = CommandContext {
+        world,
+        source,
+        output: Vec::new(),
+    };
+    if let Err(err) = (command.handler)(&mut ctx, args) {
+        ctx.reply(format!("Error: {err}"));
+        ctx.reply(format!("Usage: {name} {}", command.usage));
+    }
+    ctx.output
+}
+
+/// Names of the players in play, for completion and `list`.
+pub fn player_names(world: WorldRef) -> Vec<String> {
+    let mut names = Vec::new();
+    world.each_entity::<&Username>(|e, username| {
+        if e.has::<Play>() {
+            names.push(username.0.clone());
+        }
+    });
+    names.sort_unstable_by_key(|name| name.to_lowercase());
+    names
+}
+
+/// Finds a player in play by name, ignoring case.
+pub fn find_player(world: WorldRef, name: &str) -> Option<Entity> {
+    let mut found = None;
+    world.each_entity::<&Username>(|e, username| {
+        if e.has::<Play>() && username.0.eq_ignore_ascii_case(name) {
+            found = Some(e.id());
+        }
+    });
+    found
+}
+
+fn complete_player(world: WorldRef, args: &[&str]) -> Vec<String> {
+    if args.is_empty() { player_names(world) } else { Vec::new() }
+}
+
+fn register_builtins(commands: &mut Commands) {
+    commands.register("help", "", "Lists every command", |ctx, _| {
+        let lines: Vec<String> = ctx.world.get::<&Commands>(|commands| {
+            commands.iter()
+                .map(|(name, command)| format!("{name} {} - {}", command.usage, command.description))
+                .collect()
+        });
+        for line in lines {
+            ctx.reply(line);
+        }
+        Ok(())
+    }).completer(|world, args| {
+        if args.is_empty() {
+            world.get::<&Commands>(|commands| commands.iter().map(|(name, _)| name.to_string()).collect())
+        } else {
+            Vec::new()
+        }
+    });
+
+    commands.register("stop", "", "Disconnects everyone and stops the server", |ctx, _| {
+        ctx.world.get::<&ExitSignal>(|signal| signal.store(true, Ordering::SeqCst));
+        ctx.reply("Stopping the server");
+        Ok(())
+    });
+
+    commands.register("list", "", "Lists the players online", |ctx, _| {
+        let max = ctx.world.get::<&ServerConfig>(|config| config.max_players);
+        let names = player_names(ctx.world);
+        ctx.reply(format!("There are {} of a max of {} players online: {}", names.len(), max, names.join(", ")));
+        Ok(())
+    });
+
+    commands.register("kick", "<player> [reason]", "Disconnects a player", |ctx, args| {
+        let (name, reason) = args.split_first().context("missing player")?;
+        let player = find_player(ctx.world, name).with_context(|| format!("no player named {name} is online"))?;
+        let reason = if reason.is_empty() { "Kicked by an operator".to_string() } else { reason.join(" ") };
+        ctx.world.entity_from_id(player).set(Disconnect::new(reason.as_str()));
+        ctx.reply(format!("Kicked {name}: {reason}"));
+        Ok(())
+    }).completer(complete_player);
+
+    commands.register("say", "<message>", "Broadcasts a message to every player", |ctx, args| {
+        anyhow::ensure!(!args.is_empty(), "missing message");
+        let message = format!("[Server] {}", args.join(" "));
+        let text = TextComponent::text(&message);
+        let packet = CSystemChatMessage::new(&text, false);
+        ctx.world.each_entity::<&mut PacketEncoder>(|e, enc| {
+            if e.has::<Play>() && !e.has::<Disconnect>() {
+                if let Err(err) = enc.append_packet(&packet) {
+                    tracing::warn!("Failed to send chat message to {e}: {}", err);
+                }
+            }
+        });
+        ctx.reply(message);
+        Ok(())
+    });
+
+    commands.register("tps", "", "Shows the tick rate and time per tick", |ctx, _| {
+        let (recent, average) = ctx.world.get::<&TickTimes>(|times| (times.tps(20), times.tps(TICK_SAMPLES)));
+        let format = |tps: Option<f64>| tps.map_or_else(|| "-".to_string(), |tps| format!("{tps:.2}"));
+        ctx.reply(format!("TPS from last 20, {TICK_SAMPLES} ticks: {}, {}", format(recent), format(average)));
+        ctx.reply(format!("Last tick took {:.2} ms", ctx.world.info().delta_time * 1000.0));
+        Ok(())
+    });
+
+    commands.register("gc-stats", "", "Shows memory usage, connection counts and network counters", |ctx, _| {
+        // there is no garbage collector, the closest equivalent is the resident set
+        match fs::read_to_string("/proc/self/status") {
+            Ok(status) => {
+                for line in status.lines().filter(|line| line.starts_with("VmRSS:") || line.starts_with("VmHWM:")) {
+                    ctx.reply(line.split_whitespace().collect::<Vec<_>>().join(" "));
+                }
+            },
+            Err(_) => ctx.reply("Memory usage is not available on this platform"),
+        }
+        let (connections, online) = ctx.world.get::<&ServerStorage>(|storage| (storage.connections, storage.online_players));
+        ctx.reply(format!("Connections: {connections}, players online: {online}"));
+        if let Some(metrics) = ctx.world.get::<Option<&NetworkIo>>(|io| io.map(|io| io.metrics.clone())) {
+            ctx.reply(format!(
+                "Accepted: {}, throttled: {}, over the IP limit: {}, over the pre-login limit: {}",
+                metrics.accepted.load(Ordering::Relaxed),
+                metrics.throttled.load(Ordering::Relaxed),
+                metrics.ip_limited.load(Ordering::Relaxed),
+                metrics.pre_login_limited.load(Ordering::Relaxed),
+            ));
+        }
+        Ok(())
+    });
+
+    commands.register("reload", "", "Reloads the config file", |ctx, _| {
+        let found = ctx.world.get::<Option<&mut ConfigWatcher>>(|watcher| {
+            watcher.map(|watcher| watcher.reload_requested = true).is_some()
+        });
+        anyhow::ensure!(found, "config reloading is not enabled");
+        ctx.reply("Reloading the config on the next check");
+        Ok(())
+    });
+}
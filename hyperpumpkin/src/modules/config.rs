@@ -0,0 +1,111 @@
+use std::{fs, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime}};
+
+use flecs_ecs::prelude::*;
+
+use crate::{components::resources::NetworkIo, config::Settings, error::ConfigError, net::IoCommand};
+
+#[derive(Component)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    current: Settings,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    /// Set to reload on the next tick regardless of the file's modification time.
+    pub reload_requested: bool,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf, current: Settings) -> Self {
+        Self {
+            modified: modified(&path),
+            path,
+            current,
+            last_check: Instant::now(),
+            reload_requested: false,
+        }
+    }
+
+    pub fn current(&self) -> &Settings {
+        &self.current
+    }
+}
+
+#[derive(Component, Clone)]
+pub struct ConfigWatchSettings {
+    pub interval: Duration,
+}
+
+impl Default for ConfigWatchSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Reloads `ServerConfig`, `KeepAliveSettings`, `PhaseTimeouts`,
+/// `NetworkSettings`, `ShutdownSettings`, `QuerySettings` and `AccessSettings`
+/// when the config file changes.
+/// Expects a [`ConfigWatcher`] singleton to be set.
+#[derive(Component)]
+pub struct ConfigModule;
+
+impl Module for ConfigModule {
+    fn module(world: &World) {
+        world.set(ConfigWatchSettings::default());
+
+        world.system_named::<(&mut ConfigWatcher, &ConfigWatchSettings)>("watch_config")
+            .term_at(0).singleton()
+            .term_at(1).singleton()
+            .each_iter(|it, _, (watcher, watch_settings)| {
+                let now = Instant::now();
+                if !watcher.reload_requested && now.duration_since(watcher.last_check) < watch_settings.interval {
+                    return;
+                }
+                watcher.last_check = now;
+
+                let modified = modified(&watcher.path);
+                if !watcher.reload_requested && modified == watcher.modified {
+                    return;
+                }
+                watcher.reload_requested = false;
+
+                let _guard = tracing::info_span!("reload_config").entered();
+                match reload(&it.world(), watcher) {
+                    Ok(()) => {
+                        tracing::info!("Reloaded config from {}", watcher.path.display());
+                    },
+                    Err(err) => tracing::error!("Failed to reload config, keeping previous settings: {}", err),
+                }
+                // loading may write back missing keys, don't pick that up as another change
+                watcher.modified = modified(&watcher.path);
+            });
+    }
+}
+
+fn reload(world: &WorldRef, watcher: &mut ConfigWatcher) -> Result<(), ConfigError> {
+    let mut settings = Settings::load(&watcher.path)?;
+    let server_config = settings.server_config()?;
+
+    for key in watcher.current.retain_restart_only(&mut settings) {
+        tracing::warn!("`{}` changed, restart required to apply", key);
+    }
+
+    world.set(server_config);
+    world.set(settings.keepalive_settings());
+    world.set(settings.phase_timeouts());
+    world.set(settings.network_settings());
+    world.get::<&NetworkIo>(|io| io.handle.send(IoCommand::Settings(settings.network_settings())));
+    world.set(settings.shutdown_settings());
+    world.set(settings.access_settings());
+    if settings.query.enabled {
+        world.set(settings.query_settings());
+    }
+    world.set_target_fps(settings.server.tps);
+    watcher.current = settings;
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -0,0 +1,152 @@
+use std::{io::{self, Write}, thread, time::Duration};
+
+use crossbeam::channel::Sender;
+use flecs_ecs::prelude::*;
+use parking_lot::Mutex;
+use rustyline::{completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter, history::DefaultHistory, validate::Validator, Editor, ExternalPrinter, Helper};
+use tracing_subscriber::fmt::MakeWriter;
+
+use super::{CommandQueue, CommandRequest, CommandSource, CompletionRequest};
+
+/// How long tab completion waits for the ECS to answer.
+const COMPLETION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Prints above the prompt while the console is reading a line.
+static PRINTER: Mutex<Option<Box<dyn ExternalPrinter + Send>>> = Mutex::new(None);
+
+/// Log writer that keeps the console prompt intact, writes to stdout until
+/// the console starts.
+#[derive(Clone, Copy, Default)]
+pub struct ConsoleWriter;
+
+impl MakeWriter<'_> for ConsoleWriter {
+    type Writer = ConsoleLine;
+
+    fn make_writer(&self) -> Self::Writer {
+        ConsoleLine(Vec::new())
+    }
+}
+
+/// Buffers one log event and prints it as a whole when dropped.
+pub struct ConsoleLine(Vec<u8>);
+
+impl Write for ConsoleLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleLine {
+    fn drop(&mut self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut printer = PRINTER.lock();
+        match printer.as_mut() {
+            Some(printer) => {
+                let line = String::from_utf8_lossy(&self.0);
+                let _ = printer.print(line.trim_end_matches('\n').to_string());
+            },
+            None => {
+                let _ = io::stdout().lock().write_all(&self.0);
+            },
+        }
+    }
+}
+
+/// Completes command names and arguments through the ECS.
+struct ConsoleHelper {
+    completions: Sender<CompletionRequest>,
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+
+        let (reply, candidates) = crossbeam::channel::bounded(1);
+        let request = CompletionRequest { line: line.to_string(), reply };
+        if self.completions.send(request).is_err() {
+            return Ok((start, Vec::new()));
+        }
+        Ok((start, candidates.recv_timeout(COMPLETION_TIMEOUT).unwrap_or_default()))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/// Reads command lines from stdin on its own thread and queues them as
+/// [`CommandSource::Console`]. Expects the [`CommandModule`](super::CommandModule).
+#[derive(Component)]
+pub struct ConsoleModule;
+
+impl Module for ConsoleModule {
+    fn module(world: &World) {
+        let (commands, completions) = world.get::<&CommandQueue>(|queue| (queue.sender(), queue.completion_sender()));
+
+        let mut editor = match Editor::<ConsoleHelper, DefaultHistory>::new() {
+            Ok(editor) => editor,
+            Err(err) => {
+                tracing::warn!("Failed to start the console: {}", err);
+                return;
+            },
+        };
+        editor.set_helper(Some(ConsoleHelper { completions }));
+
+        thread::Builder::new()
+            .name("console".to_string())
+            .spawn(move || run(editor, commands))
+            .expect("spawn console thread");
+    }
+}
+
+fn run(mut editor: Editor<ConsoleHelper, DefaultHistory>, commands: Sender<CommandRequest>) {
+    match editor.create_external_printer() {
+        Ok(printer) => *PRINTER.lock() = Some(Box::new(printer)),
+        Err(err) => tracing::debug!("Console output may interleave with logs: {}", err),
+    }
+
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // the terminal is in raw mode, so Ctrl-C arrives here instead of the signal handler
+            Err(ReadlineError::Interrupted) => "stop".to_string(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                tracing::warn!("Failed to read from the console: {}", err);
+                break;
+            },
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let request = CommandRequest {
+            source: CommandSource::Console,
+            line: line.to_string(),
+            reply: None,
+        };
+        if commands.send(request).is_err() {
+            break;
+        }
+    }
+
+    PRINTER.lock().take();
+}
@@ -4,7 +4,7 @@ use flecs::OnAdd;
 use flecs_ecs::prelude::*;
 use pumpkin_protocol::{client::play::CKeepAlive, server::play::SKeepAlive, ServerPacket};
 
-use crate::components::{client::{ClientPacketQueue, PacketEncoder}, player::Play};
+use crate::{components::{client::{ClientPacketQueue, Disconnect, PacketEncoder}, player::Play}, config::Settings};
 
 #[derive(Debug, Component)]
 struct KeepAliveState {
@@ -15,14 +15,12 @@ struct KeepAliveState {
 
 #[derive(Debug, Component)]
 pub struct KeepAliveSettings {
-    period: Duration,
+    pub period: Duration,
 }
 
 impl Default for KeepAliveSettings {
     fn default() -> Self {
-        Self {
-            period: Duration::from_secs(8),
-        }
+        Settings::default().keepalive_settings()
     }
 }
 
@@ -53,6 +51,7 @@ impl Module for KeepAliveModule {
             .multi_threaded()
             .term_at(2)
             .singleton()
+            .without::<Disconnect>()
             .each_entity(|e, (enc, state, settings)| {
                 let now = Instant::now();
 
@@ -70,7 +69,7 @@ impl Module for KeepAliveModule {
                     } else {
                         let millis = settings.period.as_millis();
                         tracing::warn!("Client {e} timed out: no keepalive response after {millis}ms");
-                        e.destruct();
+                        e.set(Disconnect::new("Timed out"));
                     }
                 }
 
@@ -78,6 +77,7 @@ impl Module for KeepAliveModule {
 
         world.system_named::<(&ClientPacketQueue, &mut KeepAliveState)>("handle_keepalive")
             .multi_threaded()
+            .without::<Disconnect>()
             .each_entity(|e, (queue, keepalive)| {
                 for mut packet in queue.iter().cloned() {
                     if let Ok(packet) = SKeepAlive::read(&mut packet.bytebuf) {
@@ -89,7 +89,7 @@ impl Module for KeepAliveModule {
                                 "keepalive IDs don't match for client {e} (expected {}, got {})",
                                 keepalive.last_keepalive_id, packet.keep_alive_id,
                             );
-                            e.destruct();
+                            e.set(Disconnect::new("Invalid keepalive response"));
                         } else {
                             keepalive.got_keepalive = true;
                             // ping.0 = state.last_send.elapsed().as_millis() as i32;
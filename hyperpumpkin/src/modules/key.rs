@@ -0,0 +1,134 @@
+use std::{fs, io::Write, path::{Path, PathBuf}, thread};
+
+use anyhow::Context;
+use crossbeam::channel::{Receiver, TryRecvError};
+use flecs_ecs::prelude::*;
+use rsa::{pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding}, rand_core::OsRng, RsaPrivateKey};
+
+use crate::{components::resources::KeyPair, config::Settings};
+
+use super::Commands;
+
+const KEY_BITS: usize = 2048;
+
+/// `server.key_file`.
+#[derive(Component, Clone)]
+pub struct KeySettings {
+    /// PKCS#8 PEM file holding the server's private key.
+    pub path: PathBuf,
+}
+
+impl Default for KeySettings {
+    fn default() -> Self {
+        Settings::default().key_settings()
+    }
+}
+
+/// A key being generated off the ECS thread by `rotate-key`.
+#[derive(Component, Default)]
+struct KeyRotation {
+    pending: Option<Receiver<anyhow::Result<KeyPair>>>,
+}
+
+/// Loads the server's RSA key, generating and saving one on first start, and
+/// registers the `rotate-key` command if the [`CommandModule`](super::CommandModule)
+/// is imported. Logins that already sent an encryption request keep the key
+/// they started with.
+#[derive(Component)]
+pub struct KeyModule;
+
+impl Module for KeyModule {
+    fn module(world: &World) {
+        world.component::<KeyRotation>();
+
+        let settings = world.get::<Option<&KeySettings>>(|settings| settings.cloned().unwrap_or_default());
+        match load_or_generate(&settings.path) {
+            Ok(key_pair) => {
+                world.set(key_pair);
+            },
+            Err(err) => {
+                tracing::error!("Failed to load the server key: {:#}", err);
+                std::process::exit(1);
+            },
+        }
+        world.set(settings);
+        world.set(KeyRotation::default());
+
+        world.get::<Option<&mut Commands>>(|commands| {
+            let Some(commands) = commands else { return; };
+            commands.register("rotate-key", "", "Generates and saves a new server key", |ctx, _| {
+                let path = ctx.world.get::<&KeySettings>(|settings| settings.path.clone());
+                ctx.world.get::<&mut KeyRotation>(|rotation| {
+                    anyhow::ensure!(rotation.pending.is_none(), "a new key is already being generated");
+
+                    let (sender, receiver) = crossbeam::channel::bounded(1);
+                    thread::Builder::new()
+                        .name("rotate-key".to_string())
+                        .spawn(move || {
+                            let _ = sender.send(generate(&path));
+                        })?;
+                    rotation.pending = Some(receiver);
+                    Ok(())
+                })?;
+                ctx.reply("Generating a new server key");
+                Ok(())
+            });
+        });
+
+        world.system_named::<&mut KeyRotation>("install_key")
+            .term_at(0).singleton()
+            .each_iter(|it, _, rotation| {
+                let Some(pending) = &rotation.pending else { return; };
+                let result = match pending.try_recv() {
+                    Ok(result) => result,
+                    Err(TryRecvError::Empty) => return,
+                    Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("key generation thread exited")),
+                };
+                rotation.pending = None;
+
+                match result {
+                    Ok(key_pair) => {
+                        it.world().set(key_pair);
+                        tracing::info!("Rotated the server key, new logins use it from now on");
+                    },
+                    Err(err) => tracing::error!("Failed to rotate the server key: {:#}", err),
+                }
+            });
+    }
+}
+
+fn load_or_generate(path: &Path) -> anyhow::Result<KeyPair> {
+    if path.exists() {
+        let pem = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let private = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        tracing::debug!("loaded RSA key from {}", path.display());
+        return KeyPair::new(private);
+    }
+
+    tracing::info!("Generating a new server key at {}", path.display());
+    generate(path)
+}
+
+/// Generates a key and replaces the file at `path` with it.
+fn generate(path: &Path) -> anyhow::Result<KeyPair> {
+    let private = RsaPrivateKey::new(&mut OsRng, KEY_BITS).context("failed to generate key")?;
+    let pem = private.to_pkcs8_pem(LineEnding::LF).context("failed to encode key")?;
+
+    // written next to the target and renamed, so a crash never leaves a truncated key
+    let tmp = path.with_extension("pem.tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)
+        .with_context(|| format!("failed to create {}", tmp.display()))?;
+    file.write_all(pem.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("failed to replace {}", path.display()))?;
+
+    KeyPair::new(private)
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, fmt::Debug, net::IpAddr, sync::Arc, time::Instant};
+
+use flecs_ecs::prelude::*;
+use pumpkin_protocol::{client::login::CLoginPluginRequest, PacketError, VarInt};
+
+use crate::{components::{client::{CurrentState, Disconnect, LoginState, PacketDecoder, PacketEncoder}, player::ProfileProperty, resources::{ServerConfig, ServerStorage, VirtualHost}}, error::PacketIoError, handlers::{login::{advance_queries, answer_query}, virtual_host}};
+
+use super::PhaseTimeouts;
+
+/// Identity of a player before `CLoginSuccess`.
+#[derive(Debug, Clone)]
+pub struct LoginProfile {
+    pub uuid: uuid::Uuid,
+    pub properties: Vec<ProfileProperty>,
+    /// Forwarded by a proxy, `None` checks bans against the connection's address.
+    pub address: Option<IpAddr>,
+}
+
+/// Passed to login channel callbacks.
+pub struct LoginQueryContext<'a> {
+    pub entity: EntityView<'a>,
+    /// The virtual host the client connected through.
+    pub host: &'a VirtualHost,
+    pub config: &'a ServerConfig,
+    /// Name sent in login start.
+    pub username: &'a str,
+    /// `None` until forwarding or authentication provided it, handlers may replace it.
+    pub profile: &'a mut Option<LoginProfile>,
+}
+
+/// Called with the client's answer, `None` if it didn't understand the
+/// channel or didn't answer in time. An error refuses the login.
+pub type LoginQueryHandler = Arc<dyn Fn(&mut LoginQueryContext, Option<&[u8]>) -> anyhow::Result<()> + Send + Sync>;
+type LoginQueryRequest = Arc<dyn Fn(&LoginQueryContext) -> Option<Vec<u8>> + Send + Sync>;
+
+struct LoginChannel {
+    channel: &'static str,
+    request: LoginQueryRequest,
+    handler: LoginQueryHandler,
+}
+
+/// Channels queried on every login once the player's profile is known,
+/// right before `CLoginSuccess`.
+#[derive(Component, Default)]
+pub struct LoginChannels {
+    channels: Vec<LoginChannel>,
+}
+
+impl LoginChannels {
+    /// `request` builds the payload for a connection, `None` skips the channel for it.
+    pub fn register(
+        &mut self,
+        channel: &'static str,
+        request: impl Fn(&LoginQueryContext) -> Option<Vec<u8>> + Send + Sync + 'static,
+        handler: impl Fn(&mut LoginQueryContext, Option<&[u8]>) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) {
+        self.channels.push(LoginChannel {
+            channel,
+            request: Arc::new(request),
+            handler: Arc::new(handler),
+        });
+    }
+
+    /// Sends every registered channel that has a payload for this connection.
+    pub(crate) fn send_all(&self, ctx: &LoginQueryContext, enc: &mut PacketEncoder, queries: &mut LoginQueries) -> Result<(), PacketError> {
+        for channel in &self.channels {
+            if let Some(data) = (channel.request)(ctx) {
+                queries.send(enc, channel.channel, &data, channel.handler.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct OutstandingQuery {
+    channel: &'static str,
+    handler: LoginQueryHandler,
+    sent: Instant,
+}
+
+/// Login plugin requests a connection hasn't answered yet, by message id.
+#[derive(Clone)]
+pub struct LoginQueries {
+    next_id: i32,
+    outstanding: HashMap<i32, OutstandingQuery>,
+    /// The [`LoginChannels`] were sent, they wait for the profile.
+    pub(crate) channels_sent: bool,
+}
+
+impl Default for LoginQueries {
+    fn default() -> Self {
+        Self {
+            // ids start at a random value so a client can't answer a query before it's sent
+            next_id: rand::random(),
+            outstanding: HashMap::new(),
+            channels_sent: false,
+        }
+    }
+}
+
+impl Debug for LoginQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.outstanding.iter().map(|(id, query)| (id, query.channel)))
+            .finish()
+    }
+}
+
+impl LoginQueries {
+    pub fn send(&mut self, enc: &mut PacketEncoder, channel: &'static str, data: &[u8], handler: LoginQueryHandler) -> Result<i32, PacketError> {
+        let message_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        enc.append_packet(&CLoginPluginRequest::new(VarInt(message_id), channel, data))?;
+        self.outstanding.insert(message_id, OutstandingQuery {
+            channel,
+            handler,
+            sent: Instant::now(),
+        });
+        Ok(message_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// Removes the query and returns its channel and handler.
+    pub(crate) fn take(&mut self, message_id: i32) -> Option<(&'static str, LoginQueryHandler)> {
+        self.outstanding.remove(&message_id).map(|query| (query.channel, query.handler))
+    }
+
+    fn expired(&self, timeouts: &PhaseTimeouts) -> Vec<i32> {
+        self.outstanding.iter()
+            .filter(|(_, query)| query.sent.elapsed() >= timeouts.login_query)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+/// Holds the [`LoginChannels`] registry and answers login plugin requests
+/// the client left unanswered for `login_query` with `None`.
+#[derive(Component)]
+pub struct LoginPluginModule;
+
+impl Module for LoginPluginModule {
+    fn module(world: &World) {
+        world.set(LoginChannels::default());
+
+        world.system_named::<(&mut PacketEncoder, &mut PacketDecoder, &mut CurrentState, &ServerConfig, &ServerStorage, &PhaseTimeouts)>("login_query_timeout")
+            .multi_threaded()
+            .term_at(3).singleton()
+            .term_at(4).singleton()
+            .term_at(5).singleton()
+            .without::<Disconnect>()
+            .each_entity(|e, (enc, dec, state, config, storage, timeouts)| {
+                let CurrentState::Login(login @ LoginState::Queries { .. }) = state else { return; };
+                let LoginState::Queries { queries, .. } = &*login else { return; };
+                let expired = queries.expired(timeouts);
+                if expired.is_empty() {
+                    return;
+                }
+
+                let host = virtual_host(e, config);
+                let mut result = Ok(());
+                for message_id in expired {
+                    result = answer_query(e, host, config, login, message_id, None);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                if let Err(err) = result.and_then(|_| advance_queries(e, enc, dec, host, config, storage, login)) {
+                    let err = PacketIoError::from(err);
+                    if !matches!(err, PacketIoError::Refused(_)) {
+                        tracing::warn!("Refused login of {e}: {:#}", err);
+                    }
+                    e.set(Disconnect::new(err.reason()));
+                }
+            });
+    }
+}
@@ -1,4 +1,26 @@
+mod access;
+pub use access::{login_refusal, AccessLists, AccessModule, AccessSettings, BanDetails, IpBan, PlayerBan, WhitelistEntry};
+mod auth;
+pub use auth::AuthModule;
+mod command;
+pub use command::{dispatch, find_player, player_names, Command, CommandContext, CommandModule, CommandQueue, CommandRequest, CommandSource, Commands, CompletionRequest};
+mod config;
+pub use config::{ConfigModule, ConfigWatchSettings, ConfigWatcher};
+mod console;
+pub use console::{ConsoleModule, ConsoleWriter};
+mod key;
+pub use key::{KeyModule, KeySettings};
 mod keepalive;
 pub use keepalive::{KeepAliveModule, KeepAliveSettings};
+mod login_plugin;
+pub use login_plugin::{LoginChannels, LoginPluginModule, LoginProfile, LoginQueries, LoginQueryContext, LoginQueryHandler};
 mod net;
-pub use net::{NetworkModule, NetworkSettings};
\ No newline at end of file
+pub use net::{NetworkModule, NetworkSettings};
+mod query;
+pub use query::{QueryModule, QuerySettings};
+mod rcon;
+pub use rcon::{RconModule, RconSettings};
+mod shutdown;
+pub use shutdown::{ShutdownHooks, ShutdownModule, ShutdownSettings};
+mod timeout;
+pub use timeout::{PhaseTimeouts, TimeoutModule};
\ No newline at end of file
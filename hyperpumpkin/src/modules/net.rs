@@ -1,24 +1,42 @@
-use std::{io::Write, net::{Shutdown, SocketAddr, TcpListener}};
+use std::{net::{Shutdown, SocketAddr}, time::{Duration, Instant}};
 
-use flecs::OnRemove;
+use ipnet::IpNet;
+
+use flecs::{OnRemove, OnSet};
 use flecs_ecs::prelude::*;
-use sharded_slab::Slab;
 
-use crate::{components::{client::{ClientConnection, ClientPacketQueue, PacketDecoder, PacketEncoder, SlabId}, resources::ServerListener}, error::PacketIoError, net::{client_data, listener_accept}};
+use crate::{components::{client::{ClientConnection, ConnectionId, CurrentState, Disconnect, OutgoingBuffer, PacketDecoder, PacketEncoder}, player::Uuid, resources::NetworkIo}, config::Settings, net::{append_disconnect, flush_outgoing, receive_events, spawn_io_thread, IoCommand}};
 
+/// The `network` config section, see [`NetworkSection`](crate::config::NetworkSection)
+/// for the fields.
 #[derive(Component, Clone)]
 pub struct NetworkSettings {
-    pub address: SocketAddr
+    pub address: SocketAddr,
+    pub max_pending_bytes: usize,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub throttle_connections: u32,
+    pub throttle_window: Duration,
+    pub max_connections_per_ip: usize,
+    pub max_pre_login: usize,
+    /// `timeouts.handshake`, connections that didn't send any data, or their
+    /// PROXY header, by then are closed.
+    pub handshake_timeout: Duration,
+    pub max_bytes_per_tick: usize,
+    pub max_packets_per_tick: usize,
+    pub max_packet_size_login: usize,
+    pub max_packet_size: usize,
 }
 
 impl Default for NetworkSettings {
     fn default() -> Self {
-        Self {
-            address: ([127, 0, 0, 1], 25565).into()
-        }
+        Settings::default().network_settings()
     }
 }
 
+/// How long a disconnecting client may take to read its remaining data.
+const DISCONNECT_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Component)]
 pub struct NetworkModule;
 
@@ -37,74 +55,107 @@ impl Module for NetworkModule {
             )
         });
         
-        let listener = TcpListener::bind(settings.address).expect("bind tcp listener");
+        let io = spawn_io_thread(&settings).expect("start network io thread");
 
-        listener
-            .set_nonblocking(true)
-            .expect("failed to set nonblocking");
-    
-        world.set(ServerListener {
-            listener,
-            clients: Slab::new(),
-        });
+        world.set(settings);
+        world.set(io);
 
         // remove disconnected people
-        world.observer::<OnRemove, (&mut ClientConnection, &SlabId, &ServerListener)>()
+        world.observer::<OnRemove, (&mut ClientConnection, &ConnectionId, &mut NetworkIo)>()
             .with::<ClientConnection>()
             .term_at(2).singleton()
-            .each(| (stream, slab_id, storage)| {
-                tracing::info!("Client [ID: {}] disconnected. Cleaning up resources", slab_id.0);
+            .each(| (stream, id, io)| {
+                tracing::info!("Client [ID: {}] disconnected. Cleaning up resources", id.0);
                 let _ = stream.shutdown(Shutdown::Both);
-                storage.clients.remove(slab_id.0);
+                io.clients.remove(&id.0);
+                io.handle.send(IoCommand::Close(id.0));
+            });
+
+        // logged in connections stop counting against the pre-login limit
+        world.observer::<OnSet, (&Uuid, &ConnectionId, &NetworkIo)>()
+            .term_at(2).singleton()
+            .each(|(_, id, io)| {
+                io.handle.send(IoCommand::LoggedIn(id.0));
             });
 
-        // listener system
-        world.system_named::<&ServerListener>("listener_accept")
+        // accepted connections and decoded packets from the io thread
+        world.system_named::<(&mut NetworkIo, &NetworkSettings)>("receive_events")
             .term_at(0).singleton()
+            .term_at(1).singleton()
             .kind_id(network_receive)
-            .each_iter(|it, _, server| {
+            .each_iter(|it, _, (io, settings)| {
                 let world = it.world();
 
-                let _guard = tracing::trace_span!("listener_accept").entered();
-                match listener_accept(&world, server) {
-                    Ok(_) => {},
-                    Err(err) => {
-                        tracing::error!("{}", err);
+                let _guard = tracing::trace_span!("receive_events").entered();
+                receive_events(&world, io, settings.max_packets_per_tick);
+            });
+
+        // handshake, status and login only carry small packets
+        world.system_named::<(Option<&CurrentState>, &PacketDecoder, &NetworkSettings)>("packet_size_limit")
+            .multi_threaded()
+            .term_at(2).singleton()
+            .kind::<flecs::pipeline::PostUpdate>()
+            .each(|(state, dec, settings)| {
+                let max = match state {
+                    Some(CurrentState::HandShake | CurrentState::Status | CurrentState::Login(_) | CurrentState::Transfer) => {
+                        settings.max_packet_size_login
                     },
+                    Some(CurrentState::Config(_) | CurrentState::Play) | None => settings.max_packet_size,
                 };
+                dec.set_max_packet_size(max);
             });
-    
-        // client incoming
-        world.system_named::<(&mut ClientConnection, &mut PacketDecoder, &mut ClientPacketQueue)>("client_data")
+
+        // close once the disconnect packet and everything before it is written,
+        // a client that stopped reading gets a grace period
+        world.system_named::<(&Disconnect, Option<&OutgoingBuffer>)>("close_disconnected")
             .multi_threaded()
             .kind_id(network_receive)
-            .each_entity(|e, (stream, dec, queue)| {
-                let _guard = tracing::trace_span!("client_data").entered(); 
-                match client_data(stream, dec, queue) {
-                    Ok(_) => {},
-                    Err(err) => {
-                        if !matches!(err, PacketIoError::Disconnect) {
-                            tracing::warn!("Client data handling failed: {}.", err);
-                        }
-                        e.destruct();
-                    },
-                };
+            .each_entity(|e, (disconnect, outgoing)| {
+                let Some(sent) = disconnect.sent else { return; };
+                let flushed = outgoing.map_or(true, |outgoing| outgoing.is_empty());
+                if flushed || sent.elapsed() >= DISCONNECT_FLUSH_TIMEOUT {
+                    e.destruct();
+                }
+            });
+
+        world.system_named::<(&mut Disconnect, &mut PacketEncoder, Option<&CurrentState>)>("send_disconnect")
+            .multi_threaded()
+            .kind::<flecs::pipeline::PostUpdate>()
+            .each_entity(|e, (disconnect, enc, state)| {
+                if disconnect.sent.is_some() {
+                    return;
+                }
+                disconnect.sent = Some(Instant::now());
+
+                tracing::info!("Disconnecting client {e}: {}", disconnect.reason.to_legacy_lossy());
+                if let Err(err) = append_disconnect(enc, state, &disconnect.reason) {
+                    tracing::warn!("Failed to encode disconnect packet: {}.", err);
+                }
             });
     
-        // Flush
-        world.system_named::<(&mut ClientConnection, &mut PacketEncoder)>("flush")
+        // Flush, a failed socket can't receive a disconnect packet anymore
+        world.system_named::<(&mut ClientConnection, &mut PacketEncoder, &mut OutgoingBuffer, &NetworkSettings)>("flush")
             .multi_threaded()
+            .term_at(3).singleton()
             .kind::<flecs::pipeline::PostUpdate>()
-            .each_entity(|e, (stream, enc)| {
+            .each_entity(|e, (stream, enc, outgoing, settings)| {
                 let _guard = tracing::trace_span!("flush").entered();
-                let data = enc.take();
-                match stream.write_all(&data) {
-                    Ok(_) => {},
-                    Err(err) => {
-                        tracing::warn!("Failed to write data to client stream: {}.", err);
-                        e.destruct();
-                    },
-                };
+                outgoing.unsplit(enc.take());
+                if let Err(err) = flush_outgoing(stream, outgoing) {
+                    tracing::warn!("Failed to write data to client stream: {}.", err);
+                    e.destruct();
+                    return;
+                }
+
+                // the reason queues behind the backlog, it only arrives if the
+                // client catches up before `close_disconnected` gives up on it
+                if outgoing.len() > settings.max_pending_bytes && !e.has::<Disconnect>() {
+                    tracing::warn!(
+                        "Client {e} is too slow: {} bytes pending (limit {}).",
+                        outgoing.len(), settings.max_pending_bytes,
+                    );
+                    e.set(Disconnect::new("Connection too slow"));
+                }
             });
 
     }
@@ -0,0 +1,202 @@
+use std::{net::{SocketAddr, UdpSocket}, time::{Duration, Instant}};
+
+use flecs_ecs::prelude::*;
+use sha1::{Digest, Sha1};
+
+use crate::{components::{player::{HiddenFromStatus, Play, Username}, resources::{ServerConfig, ServerStorage}}, config::Settings, interrupted, modules::NetworkSettings, would_block};
+
+const MAGIC: [u8; 2] = [0xFE, 0xFD];
+const TYPE_HANDSHAKE: u8 = 9;
+const TYPE_STAT: u8 = 0;
+/// Basic stat requests end after the challenge token, full stat adds 4 bytes of padding.
+const BASIC_STAT_LEN: usize = 11;
+const FULL_STAT_LEN: usize = 15;
+/// Datagrams handled per tick, the rest waits in the socket buffer.
+const MAX_REQUESTS_PER_TICK: usize = 256;
+
+/// The `query` config section, see [`QuerySection`](crate::config::QuerySection).
+#[derive(Component, Clone)]
+pub struct QuerySettings {
+    pub address: SocketAddr,
+    pub challenge_interval: Duration,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        Settings::default().query_settings()
+    }
+}
+
+/// Challenge tokens are derived from the client's address and a secret,
+/// so spoofed handshakes don't cost any memory.
+#[derive(Component)]
+struct QuerySocket {
+    socket: UdpSocket,
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    rotated: Instant,
+}
+
+impl QuerySocket {
+    fn challenge(secret: &[u8; 16], addr: SocketAddr) -> i32 {
+        let digest = Sha1::new()
+            .chain_update(secret)
+            .chain_update(addr.to_string())
+            .finalize();
+        i32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    fn is_valid(&self, challenge: i32, addr: SocketAddr) -> bool {
+        challenge == Self::challenge(&self.secret, addr) || challenge == Self::challenge(&self.previous_secret, addr)
+    }
+
+    fn rotate(&mut self, interval: Duration) {
+        if self.rotated.elapsed() >= interval {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+/// Answers GameSpy4 UDP queries, the basic and full stat used by server lists.
+/// Reads an optional [`QuerySettings`] singleton when imported.
+#[derive(Component)]
+pub struct QueryModule;
+
+impl Module for QueryModule {
+    fn module(world: &World) {
+        world.component::<QuerySocket>();
+
+        let settings = world.get::<Option<&QuerySettings>>(|settings| {
+            settings.map_or(QuerySettings::default(), |f| f.clone())
+        });
+
+        let socket = UdpSocket::bind(settings.address)
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .expect("bind query socket");
+        tracing::info!("Query listening on {}", settings.address);
+
+        world.set(settings);
+        world.set(QuerySocket {
+            socket,
+            secret: rand::random(),
+            previous_secret: rand::random(),
+            rotated: Instant::now(),
+        });
+
+        world.system_named::<(&mut QuerySocket, &QuerySettings, &ServerConfig, &ServerStorage, &NetworkSettings)>("query")
+            .term_at(0).singleton()
+            .term_at(1).singleton()
+            .term_at(2).singleton()
+            .term_at(3).singleton()
+            .term_at(4).singleton()
+            .each_iter(|it, _, (query, settings, config, storage, network)| {
+                query.rotate(settings.challenge_interval);
+
+                let mut buf = [0u8; 64];
+                for _ in 0..MAX_REQUESTS_PER_TICK {
+                    let (len, addr) = match query.socket.recv_from(&mut buf) {
+                        Ok(ok) => ok,
+                        Err(ref err) if would_block(err) => break,
+                        Err(ref err) if interrupted(err) => continue,
+                        Err(err) => {
+                            tracing::warn!("Failed to receive query: {}", err);
+                            break;
+                        },
+                    };
+
+                    let Some(response) = respond(&it.world(), query, &buf[..len], addr, config, storage, network) else { continue; };
+                    if let Err(err) = query.socket.send_to(&response, addr) {
+                        if !would_block(&err) {
+                            tracing::debug!("Failed to answer query from {}: {}", addr, err);
+                        }
+                    }
+                }
+            });
+    }
+}
+
+/// Builds the answer to one datagram, `None` for anything malformed or with
+/// an invalid challenge.
+fn respond(
+    world: &WorldRef,
+    query: &QuerySocket,
+    request: &[u8],
+    addr: SocketAddr,
+    config: &ServerConfig,
+    storage: &ServerStorage,
+    network: &NetworkSettings,
+) -> Option<Vec<u8>> {
+    if request.len() < 7 || request[..2] != MAGIC {
+        return None;
+    }
+    let kind = request[2];
+    let session = &request[3..7];
+
+    let mut response = vec![kind];
+    response.extend_from_slice(session);
+
+    match (kind, request.len()) {
+        (TYPE_HANDSHAKE, _) => {
+            let challenge = QuerySocket::challenge(&query.secret, addr);
+            put_string(&mut response, &challenge.to_string());
+        },
+        (TYPE_STAT, BASIC_STAT_LEN | FULL_STAT_LEN) => {
+            let challenge = i32::from_be_bytes(request[7..11].try_into().ok()?);
+            if !query.is_valid(challenge, addr) {
+                return None;
+            }
+
+            let motd = config.default_host.description.to_legacy_lossy();
+            let online = storage.online_players.to_string();
+            let max = config.max_players.to_string();
+            let port = network.address.port();
+            let ip = network.address.ip().to_string();
+
+            if request.len() == BASIC_STAT_LEN {
+                for value in [motd.as_str(), "SMP", "world", &online, &max] {
+                    put_string(&mut response, value);
+                }
+                response.extend_from_slice(&port.to_le_bytes());
+                put_string(&mut response, &ip);
+            } else {
+                response.extend_from_slice(b"splitnum\0\x80\0");
+                let port = port.to_string();
+                let pairs = [
+                    ("hostname", motd.as_str()),
+                    ("gametype", "SMP"),
+                    ("game_id", "MINECRAFT"),
+                    ("version", pumpkin_protocol::CURRENT_MC_VERSION),
+                    ("plugins", ""),
+                    ("map", "world"),
+                    ("numplayers", &online),
+                    ("maxplayers", &max),
+                    ("hostport", &port),
+                    ("hostip", &ip),
+                ];
+                for (key, value) in pairs {
+                    put_string(&mut response, key);
+                    put_string(&mut response, value);
+                }
+                response.push(0);
+
+                response.extend_from_slice(b"\x01player_\0\0");
+                // players that opted out of server listings are left out here as well
+                world.each_entity::<&Username>(|e, username| {
+                    if e.has::<Play>() && !e.has::<HiddenFromStatus>() {
+                        put_string(&mut response, &username.0);
+                    }
+                });
+                response.push(0);
+            }
+        },
+        _ => return None,
+    }
+    Some(response)
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend(value.bytes().filter(|byte| *byte != 0));
+    buf.push(0);
+}
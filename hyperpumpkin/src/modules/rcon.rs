@@ -0,0 +1,183 @@
+use std::{io::{self, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread, time::Duration};
+
+use anyhow::{bail, ensure};
+use crossbeam::channel::Sender;
+use flecs_ecs::prelude::*;
+
+use crate::{config::Settings, modules::{CommandQueue, CommandRequest, CommandSource}};
+
+const TYPE_RESPONSE: i32 = 0;
+const TYPE_COMMAND: i32 = 2;
+const TYPE_AUTH_RESPONSE: i32 = 2;
+const TYPE_AUTH: i32 = 3;
+/// Id, type and the two terminating nulls.
+const MIN_PACKET_LEN: usize = 10;
+const MAX_PACKET_LEN: usize = 4096;
+/// Longer output is split over several response packets.
+const MAX_RESPONSE_BODY: usize = 4096;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `rcon` config section, see [`RconSection`](crate::config::RconSection).
+#[derive(Component, Clone)]
+pub struct RconSettings {
+    pub address: SocketAddr,
+    pub password: String,
+    pub max_sessions: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for RconSettings {
+    fn default() -> Self {
+        Settings::default().rcon_settings()
+    }
+}
+
+/// Source RCON server, command lines go through the same dispatcher as the
+/// console. Reads an optional [`RconSettings`] singleton when imported and
+/// requires the [`CommandModule`](super::CommandModule).
+#[derive(Component)]
+pub struct RconModule;
+
+impl Module for RconModule {
+    fn module(world: &World) {
+        let settings = world.get::<Option<&RconSettings>>(|settings| {
+            settings.map_or(RconSettings::default(), |f| f.clone())
+        });
+        let commands = world.get::<&CommandQueue>(|queue| queue.sender());
+
+        let listener = TcpListener::bind(settings.address).expect("bind rcon listener");
+        tracing::info!("RCON listening on {}", settings.address);
+
+        thread::Builder::new()
+            .name("rcon".to_string())
+            .spawn({
+                let settings = settings.clone();
+                move || accept_sessions(listener, settings, commands)
+            })
+            .expect("spawn rcon thread");
+
+        world.set(settings);
+    }
+}
+
+fn accept_sessions(listener: TcpListener, settings: RconSettings, commands: Sender<CommandRequest>) {
+    let sessions = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept RCON connection: {}", err);
+                continue;
+            },
+        };
+
+        if sessions.fetch_add(1, Ordering::SeqCst) >= settings.max_sessions {
+            sessions.fetch_sub(1, Ordering::SeqCst);
+            // dropping the stream closes it
+            continue;
+        }
+
+        let spawned = thread::Builder::new()
+            .name("rcon-session".to_string())
+            .spawn({
+                let settings = settings.clone();
+                let commands = commands.clone();
+                let sessions = sessions.clone();
+                move || {
+                    let addr = stream.peer_addr().ok();
+                    if let Err(err) = session(stream, &settings, &commands) {
+                        tracing::debug!("RCON session {:?} ended: {}", addr, err);
+                    }
+                    sessions.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+        if let Err(err) = spawned {
+            tracing::error!("Failed to spawn RCON session: {}", err);
+            sessions.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn session(mut stream: TcpStream, settings: &RconSettings, commands: &Sender<CommandRequest>) -> anyhow::Result<()> {
+    let addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(settings.idle_timeout))?;
+
+    let mut authenticated = false;
+    loop {
+        let (id, kind, body) = read_packet(&mut stream)?;
+        match kind {
+            TYPE_AUTH => {
+                if body != settings.password {
+                    tracing::warn!("Failed RCON login from {}", addr);
+                    write_packet(&mut stream, -1, TYPE_AUTH_RESPONSE, "")?;
+                    bail!("wrong password");
+                }
+                authenticated = true;
+                tracing::info!("RCON login from {}", addr);
+                write_packet(&mut stream, id, TYPE_AUTH_RESPONSE, "")?;
+            },
+            TYPE_COMMAND => {
+                ensure!(authenticated, "command before authentication");
+
+                let (reply, output) = crossbeam::channel::bounded(1);
+                commands.send(CommandRequest {
+                    source: CommandSource::Rcon(addr),
+                    line: body,
+                    reply: Some(reply),
+                })?;
+                let output = output.recv_timeout(COMMAND_TIMEOUT)
+                    .unwrap_or_else(|_| vec!["Command timed out".to_string()])
+                    .join("\n");
+
+                write_response(&mut stream, id, &output)?;
+            },
+            _ => {
+                write_packet(&mut stream, id, TYPE_RESPONSE, &format!("Unknown request {kind:#x}"))?;
+            },
+        }
+    }
+}
+
+fn read_packet(stream: &mut TcpStream) -> anyhow::Result<(i32, i32, String)> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = i32::from_le_bytes(len);
+    ensure!(
+        (MIN_PACKET_LEN as i32..=MAX_PACKET_LEN as i32).contains(&len),
+        "invalid packet length {len}",
+    );
+
+    let mut packet = vec![0u8; len as usize];
+    stream.read_exact(&mut packet)?;
+    let id = i32::from_le_bytes(packet[0..4].try_into()?);
+    let kind = i32::from_le_bytes(packet[4..8].try_into()?);
+    let body = &packet[8..packet.len() - 2];
+    Ok((id, kind, String::from_utf8_lossy(body).into_owned()))
+}
+
+fn write_packet(stream: &mut TcpStream, id: i32, kind: i32, body: &str) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(body.len() + 14);
+    packet.extend_from_slice(&((body.len() + MIN_PACKET_LEN) as i32).to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&kind.to_le_bytes());
+    packet.extend_from_slice(body.as_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    stream.write_all(&packet)
+}
+
+/// Splits the output on character boundaries into packets with the request's id.
+fn write_response(stream: &mut TcpStream, id: i32, output: &str) -> io::Result<()> {
+    let mut rest = output;
+    loop {
+        let mut end = rest.len().min(MAX_RESPONSE_BODY);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        write_packet(stream, id, TYPE_RESPONSE, chunk)?;
+        if tail.is_empty() {
+            return Ok(());
+        }
+        rest = tail;
+    }
+}
@@ -0,0 +1,93 @@
+use std::{sync::atomic::Ordering, time::{Duration, Instant}};
+
+use flecs_ecs::prelude::*;
+
+use crate::{components::{client::{ClientConnection, Disconnect}, resources::{ExitSignal, NetworkIo}}, config::Settings, net::IoCommand};
+
+/// `server.shutdown_message` and `server.shutdown_timeout`.
+#[derive(Component, Clone)]
+pub struct ShutdownSettings {
+    pub message: String,
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Settings::default().shutdown_settings()
+    }
+}
+
+type ShutdownHook = Box<dyn FnMut(&WorldRef) + Send + Sync>;
+
+/// Run in registration order once every client is gone or the timeout passed,
+/// right before the world quits.
+#[derive(Component, Default)]
+pub struct ShutdownHooks {
+    hooks: Vec<(&'static str, ShutdownHook)>,
+}
+
+impl ShutdownHooks {
+    pub fn register(&mut self, name: &'static str, hook: impl FnMut(&WorldRef) + Send + Sync + 'static) {
+        self.hooks.push((name, Box::new(hook)));
+    }
+}
+
+#[derive(Component, Default)]
+struct ShutdownState {
+    started: Option<Instant>,
+}
+
+/// Turns the `ExitSignal` into a graceful shutdown: stop accepting, kick every
+/// client, wait for them to close, run the shutdown hooks and quit.
+#[derive(Component)]
+pub struct ShutdownModule;
+
+impl Module for ShutdownModule {
+    fn module(world: &World) {
+        world.set(ShutdownSettings::default());
+        world.set(ShutdownHooks::default());
+        world.set(ShutdownState::default());
+
+        world.system_named::<(&ExitSignal, &mut ShutdownState, &ShutdownSettings, &mut ShutdownHooks, &NetworkIo)>("shutdown")
+            .term_at(0).singleton()
+            .term_at(1).singleton()
+            .term_at(2).singleton()
+            .term_at(3).singleton()
+            .term_at(4).singleton()
+            .each_iter(|it, _, (signal, state, settings, hooks, io)| {
+                if !signal.load(Ordering::SeqCst) {
+                    return;
+                }
+                let world = it.world();
+
+                let Some(started) = state.started else {
+                    tracing::info!("Ctrl-C detected, shutting down.");
+                    state.started = Some(Instant::now());
+
+                    io.handle.send(IoCommand::StopAccepting);
+                    world.each_entity::<&ClientConnection>(|e, _| {
+                        if !e.has::<Disconnect>() {
+                            e.set(Disconnect::new(settings.message.clone()));
+                        }
+                    });
+                    return;
+                };
+
+                let timed_out = started.elapsed() >= settings.timeout;
+                if !io.clients.is_empty() && !timed_out {
+                    return;
+                }
+                if timed_out {
+                    tracing::warn!("{} clients still connected after {:?}, quitting anyway", io.clients.len(), settings.timeout);
+                }
+
+                for (name, hook) in hooks.hooks.iter_mut() {
+                    tracing::info!("Running shutdown hook {}", name);
+                    hook(&world);
+                }
+
+                tracing::info!("Shutdown complete, quitting.");
+                world.quit();
+            });
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+use flecs::OnAdd;
+use flecs_ecs::prelude::*;
+
+use crate::{components::client::{CurrentState, Disconnect}, config::Settings};
+
+/// How long a client may stay in each phase before it is disconnected, the
+/// `timeouts` config section, see [`TimeoutSection`](crate::config::TimeoutSection).
+#[derive(Debug, Component, Clone)]
+pub struct PhaseTimeouts {
+    pub handshake: Duration,
+    pub status: Duration,
+    pub login: Duration,
+    pub config: Duration,
+    pub login_query: Duration,
+}
+
+impl Default for PhaseTimeouts {
+    fn default() -> Self {
+        Settings::default().phase_timeouts()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    HandShake,
+    Status,
+    Login,
+    Config,
+}
+
+impl Phase {
+    fn of(state: &CurrentState) -> Option<Self> {
+        match state {
+            CurrentState::HandShake | CurrentState::Transfer => Some(Phase::HandShake),
+            CurrentState::Status => Some(Phase::Status),
+            CurrentState::Login(_) => Some(Phase::Login),
+            CurrentState::Config(_) => Some(Phase::Config),
+            CurrentState::Play => None,
+        }
+    }
+
+    fn timeout(self, timeouts: &PhaseTimeouts) -> Duration {
+        match self {
+            Phase::HandShake => timeouts.handshake,
+            Phase::Status => timeouts.status,
+            Phase::Login => timeouts.login,
+            Phase::Config => timeouts.config,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            Phase::Login => "Took too long to log in",
+            _ => "Timed out",
+        }
+    }
+}
+
+/// Left in place once `CurrentState` is removed for play, it's only queried
+/// together with it.
+#[derive(Debug, Component)]
+struct PhaseTimer {
+    phase: Option<Phase>,
+    since: Instant,
+}
+
+/// Disconnects clients that stall before reaching play, play is covered by
+/// the [`KeepAliveModule`](super::KeepAliveModule).
+#[derive(Component)]
+pub struct TimeoutModule;
+
+impl Module for TimeoutModule {
+    fn module(world: &World) {
+        world.set(PhaseTimeouts::default());
+
+        world.observer_named::<OnAdd, ()>("add_phase_timer")
+            .with::<CurrentState>()
+            .each_entity(|e, _| {
+                e.set(PhaseTimer {
+                    phase: None,
+                    since: Instant::now(),
+                });
+            });
+
+        world.system_named::<(&CurrentState, &mut PhaseTimer, &PhaseTimeouts)>("phase_timeout")
+            .multi_threaded()
+            .term_at(2)
+            .singleton()
+            .without::<Disconnect>()
+            .each_entity(|e, (state, timer, timeouts)| {
+                let phase = Phase::of(state);
+                if phase != timer.phase {
+                    timer.phase = phase;
+                    timer.since = Instant::now();
+                    return;
+                }
+                let Some(phase) = phase else { return; };
+
+                let timeout = phase.timeout(timeouts);
+                if timer.since.elapsed() >= timeout {
+                    tracing::warn!("Client {e} timed out: still in {state} after {}ms", timeout.as_millis());
+                    e.set(Disconnect::new(phase.reason()));
+                }
+            });
+    }
+}
@@ -1,73 +1,753 @@
-use std::io::Read;
+use std::{collections::HashMap, io::{self, Read, Write}, net::{self, IpAddr, SocketAddr}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, thread, time::{Duration, Instant}};
 
-use anyhow::bail;
+use bytes::Buf;
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
 use flecs_ecs::prelude::*;
+use mio::{net::{TcpListener, TcpStream}, Events, Interest, Poll, Token, Waker};
+use pumpkin_core::text::TextComponent;
+use pumpkin_protocol::{client::{config::CConfigDisconnect, login::CLoginDisconnect, play::CPlayDisconnect}, PacketError, RawPacket};
+use valence_text::Text;
 
-use crate::{components::{client::{ClientConnection, ClientPacketQueue, CurrentState, PacketDecoder, PacketEncoder, RemoteAddress, SlabId}, resources::ServerListener}, error::PacketIoError, interrupted, would_block};
+use crate::{components::{client::{ClientConnection, ClientPacketQueue, ConnectionId, CurrentState, Disconnect, LegacyPing, OutgoingBuffer, PacketDecoder, PacketEncoder, RemoteAddress, TrustedProxy}, resources::NetworkIo}, error::PacketIoError, interrupted, modules::NetworkSettings, would_block};
 
-pub fn listener_accept(world: &WorldRef, server: &ServerListener) -> anyhow::Result<()> {
-    loop {
-        let (stream, addr) = match server.listener.accept() {
-            Ok(ok) => ok,
-            Err(ref err) if would_block(err) => break,
-            Err(ref err) if interrupted(err) => continue,
-            Err(err) => return Err(err.into()),
-        };
+mod proxy;
+
+const LISTENER: Token = Token(usize::MAX - 1);
+const WAKER: Token = Token(usize::MAX);
+/// How often pending connections are checked for overdue first bytes.
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sent from the IO thread to the ECS.
+pub enum NetEvent {
+    Accepted {
+        id: usize,
+        stream: net::TcpStream,
+        addr: SocketAddr,
+        decoder: PacketDecoder,
+        /// The connection opened with a pre-netty server list ping instead of a handshake.
+        legacy_ping: Option<LegacyPing>,
+        /// The peer is one of `trusted_proxies`.
+        trusted: bool,
+    },
+    Packets {
+        id: usize,
+        packets: Vec<RawPacket>,
+    },
+    Closed {
+        id: usize,
+        error: PacketIoError,
+    },
+}
+
+/// Sent from the ECS to the IO thread.
+pub enum IoCommand {
+    Close(usize),
+    /// The connection finished logging in and no longer counts as pre-login.
+    LoggedIn(usize),
+    /// Replaces the connection limits after a config reload.
+    Settings(NetworkSettings),
+    /// Closes the listener, existing connections are kept.
+    StopAccepting,
+}
+
+/// Connection counters kept by the IO thread.
+#[derive(Debug, Default)]
+pub struct NetworkMetrics {
+    pub accepted: AtomicU64,
+    /// Rejected for opening too many connections within the throttle window.
+    pub throttled: AtomicU64,
+    /// Rejected for having too many connections open from the same address.
+    pub ip_limited: AtomicU64,
+    /// Rejected because too many connections hadn't logged in yet.
+    pub pre_login_limited: AtomicU64,
+}
+
+/// Tick counter shared with the IO thread, read budgets are refilled every tick.
+#[derive(Default)]
+struct TickSync {
+    tick: AtomicU64,
+    /// Set by the IO thread while it has deferred reads waiting for the next tick.
+    waiting: AtomicBool,
+}
+
+/// Handle for sending commands to the IO thread.
+#[derive(Clone)]
+pub struct IoHandle {
+    commands: Sender<IoCommand>,
+    waker: Arc<Waker>,
+    ticks: Arc<TickSync>,
+}
+
+impl IoHandle {
+    /// Starts a new tick, waking the IO thread if reads were deferred.
+    pub fn next_tick(&self) {
+        self.ticks.tick.fetch_add(1, Ordering::SeqCst);
+        if self.ticks.waiting.swap(false, Ordering::SeqCst) {
+            if let Err(err) = self.waker.wake() {
+                tracing::error!("failed to wake io thread: {}", err);
+            }
+        }
+    }
+
+    pub fn send(&self, command: IoCommand) {
+        if self.commands.send(command).is_ok() {
+            if let Err(err) = self.waker.wake() {
+                tracing::error!("failed to wake io thread: {}", err);
+            }
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    decoder: PacketDecoder,
+    /// The peer's address, the client's once a trusted proxy's PROXY header
+    /// named it.
+    ip: IpAddr,
+    trusted: bool,
+    /// Counted against the per address limits of `ip`. Trusted proxies are
+    /// exempt, the clients behind them are counted once their address is known.
+    limited: bool,
+    logged_in: bool,
+    /// Bytes left to read this tick, `None` if unlimited.
+    budget: Option<usize>,
+    budget_tick: u64,
+    /// Set until the ECS is told about the connection, which waits for the
+    /// first bytes to tell a legacy ping from a handshake, and for the PROXY
+    /// protocol header when that's enabled.
+    pending: Option<PendingConnection>,
+    /// Answered by the ECS, anything the client sends afterwards is dropped.
+    legacy: bool,
+}
+
+struct PendingConnection {
+    write_half: net::TcpStream,
+    addr: SocketAddr,
+    accepted: Instant,
+    header: Vec<u8>,
+    /// Client address and header length once the PROXY header was parsed.
+    proxied: Option<(SocketAddr, usize)>,
+}
+
+struct IpState {
+    connections: usize,
+    window_start: Instant,
+    window_connections: u32,
+}
+
+struct IoThread {
+    settings: NetworkSettings,
+    poll: Poll,
+    listener: Option<TcpListener>,
+    /// Ids are never reused, so late commands can't hit a newer connection.
+    connections: HashMap<usize, Connection>,
+    next_id: usize,
+    ips: HashMap<IpAddr, IpState>,
+    last_sweep: Instant,
+    last_pending_check: Instant,
+    pre_login: usize,
+    metrics: Arc<NetworkMetrics>,
+    ticks: Arc<TickSync>,
+    /// Connections that ran out of read budget, retried once the tick changes.
+    deferred: Vec<usize>,
+    deferred_tick: u64,
+    events: Sender<NetEvent>,
+    commands: Receiver<IoCommand>,
+}
+
+/// Binds the listener and spawns the thread that owns every socket's read side.
+/// Connections are only woken when the OS reports readiness, decoded packets are
+/// handed to the ECS through the returned [`NetworkIo`].
+pub fn spawn_io_thread(settings: &NetworkSettings) -> io::Result<NetworkIo> {
+    let poll = Poll::new()?;
+    let mut listener = TcpListener::bind(settings.address)?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    let (event_tx, event_rx) = crossbeam::channel::unbounded();
+    let (command_tx, command_rx) = crossbeam::channel::unbounded();
+    let metrics = Arc::new(NetworkMetrics::default());
+    let ticks = Arc::new(TickSync::default());
+
+    let io = IoThread {
+        settings: settings.clone(),
+        poll,
+        listener: Some(listener),
+        connections: HashMap::new(),
+        next_id: 0,
+        ips: HashMap::new(),
+        last_sweep: Instant::now(),
+        last_pending_check: Instant::now(),
+        pre_login: 0,
+        metrics: metrics.clone(),
+        ticks: ticks.clone(),
+        deferred: Vec::new(),
+        deferred_tick: 0,
+        events: event_tx,
+        commands: command_rx,
+    };
+
+    thread::Builder::new()
+        .name("network-io".to_string())
+        .spawn(move || io.run())?;
 
-        if stream.set_nonblocking(true).is_err() { continue; }
-        if stream.set_nodelay(true).is_err() { continue; }
-        
-        let Some(entry) = server.clients.vacant_entry() else {
-            bail!("failed to get vacant entry");
+    Ok(NetworkIo {
+        events: event_rx,
+        handle: IoHandle {
+            commands: command_tx,
+            waker,
+            ticks,
+        },
+        metrics,
+        clients: HashMap::new(),
+        received: Vec::new(),
+    })
+}
+
+impl IoThread {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let mut timeout = Some(PENDING_CHECK_INTERVAL);
+            if !self.deferred.is_empty() {
+                // set before checking the tick, so a tick starting in between wakes us
+                self.ticks.waiting.store(true, Ordering::SeqCst);
+                if self.ticks.tick.load(Ordering::SeqCst) != self.deferred_tick {
+                    timeout = Some(Duration::ZERO);
+                }
+            }
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if interrupted(&err) {
+                    continue;
+                }
+                tracing::error!("io thread poll failed: {}", err);
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => {
+                        if !self.commands() {
+                            return;
+                        }
+                    },
+                    Token(id) => self.read(id),
+                }
+            }
+
+            if !self.deferred.is_empty() && self.ticks.tick.load(Ordering::SeqCst) != self.deferred_tick {
+                for id in std::mem::take(&mut self.deferred) {
+                    self.read(id);
+                }
+            }
+
+            self.expire_pending();
+        }
+    }
+
+    /// Closes connections that didn't send their first bytes in time. Every
+    /// connection is scanned, so this runs at most once per check interval
+    /// however often the poll wakes up.
+    fn expire_pending(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_pending_check) < PENDING_CHECK_INTERVAL {
+            return;
+        }
+        self.last_pending_check = now;
+
+        let timeout = self.settings.handshake_timeout;
+        let expired: Vec<usize> = self.connections.iter()
+            .filter(|(_, connection)| connection.pending.as_ref().is_some_and(|pending| pending.accepted.elapsed() >= timeout))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            tracing::debug!("Closing connection {id}, no data within {}ms", timeout.as_millis());
+            self.close(id);
+        }
+    }
+
+    fn accept(&mut self) {
+        self.sweep();
+        loop {
+            let Some(listener) = &self.listener else { return; };
+            let (stream, addr) = match listener.accept() {
+                Ok(ok) => ok,
+                Err(ref err) if would_block(err) => break,
+                Err(ref err) if interrupted(err) => continue,
+                Err(err) => {
+                    tracing::error!("failed to accept connection: {}", err);
+                    break;
+                },
+            };
+
+            let trusted = self.settings.trusted_proxies.iter().any(|net| net.contains(&addr.ip()));
+            if !self.admit(addr.ip(), trusted) {
+                // dropping the stream closes it before anything was allocated for it
+                continue;
+            }
+            self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+
+            if let Err(err) = self.register(stream, addr, trusted) {
+                tracing::warn!("failed to register connection from {}: {}", addr, err);
+                self.release(addr.ip(), !trusted, false);
+            }
+        }
+    }
+
+    /// Checks the connection limits and counts the connection if it's let in.
+    fn admit(&mut self, ip: IpAddr, trusted: bool) -> bool {
+        let settings = &self.settings;
+        if settings.max_pre_login != 0 && self.pre_login >= settings.max_pre_login {
+            self.metrics.pre_login_limited.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        if !trusted && !admit_ip(&mut self.ips, settings, &self.metrics, ip) {
+            return false;
+        }
+
+        self.pre_login += 1;
+        true
+    }
+
+    /// Undoes the counting done by [`IoThread::admit`] and [`admit_ip`].
+    fn release(&mut self, ip: IpAddr, limited: bool, logged_in: bool) {
+        if !logged_in {
+            self.pre_login -= 1;
+        }
+        if limited {
+            if let Some(state) = self.ips.get_mut(&ip) {
+                state.connections -= 1;
+            }
+        }
+    }
+
+    /// Forgets addresses without open connections whose throttle window passed.
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        let window = self.settings.throttle_window;
+        if now.duration_since(self.last_sweep) < window {
+            return;
+        }
+        self.last_sweep = now;
+        self.ips.retain(|_, state| state.connections > 0 || now.duration_since(state.window_start) < window);
+    }
+
+    fn register(&mut self, stream: TcpStream, addr: SocketAddr, trusted: bool) -> anyhow::Result<()> {
+        stream.set_nodelay(true)?;
+
+        // the ECS writes through its own handle to the same socket
+        let stream = net::TcpStream::from(stream);
+        let write_half = stream.try_clone()?;
+        let mut stream = TcpStream::from_std(stream);
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.poll.registry().register(&mut stream, Token(id), Interest::READABLE)?;
+        let decoder = PacketDecoder::default();
+        decoder.set_max_packet_size(self.settings.max_packet_size_login);
+        let connection = Connection {
+            stream,
+            decoder,
+            ip: addr.ip(),
+            trusted,
+            limited: !trusted,
+            logged_in: false,
+            budget: None,
+            budget_tick: 0,
+            pending: Some(PendingConnection {
+                write_half,
+                addr,
+                accepted: Instant::now(),
+                header: Vec::new(),
+                proxied: None,
+            }),
+            legacy: false,
         };
-        
-        let client = world.entity()
-            .set(ClientConnection(stream))
-            .set(RemoteAddress(addr.ip()))
-            .set(PacketEncoder::default())
-            .set(PacketDecoder::default())
-            .set(ClientPacketQueue::default())
-            .set(SlabId(entry.key()))
-            .set(CurrentState::HandShake);
+        self.connections.insert(id, connection);
+        Ok(())
+    }
+
+    fn read(&mut self, id: usize) {
+        let events = &self.events;
+        let Some(connection) = self.connections.get_mut(&id) else { return; };
+        let _guard = tracing::trace_span!("client_data").entered();
 
-        entry.insert(client.id());
+        let tick = self.ticks.tick.load(Ordering::SeqCst);
+        if connection.budget_tick != tick || connection.budget.is_none() {
+            connection.budget_tick = tick;
+            let max = self.settings.max_bytes_per_tick;
+            connection.budget = (max != 0).then_some(max);
+        }
+
+        match read_connection(connection) {
+            Ok(true) => {},
+            Ok(false) => {
+                // the rest stays in the socket, which pushes back on the client
+                self.deferred.push(id);
+                self.deferred_tick = tick;
+            },
+            Err(error) => {
+                let announced = connection.pending.is_none();
+                self.close(id);
+                if announced {
+                    self.send(NetEvent::Closed { id, error });
+                }
+                return;
+            },
+        }
+
+        if let Some(pending) = &mut connection.pending {
+            match first_bytes(pending, self.settings.proxy_protocol, connection.trusted) {
+                Ok(None) => return,
+                Ok(Some((addr, offset))) => {
+                    // a proxy's own connections, like health checks, stay exempt
+                    if !connection.limited && addr.ip() != connection.ip {
+                        if !admit_ip(&mut self.ips, &self.settings, &self.metrics, addr.ip()) {
+                            tracing::debug!("Closing connection {id} from {addr}, over the per address limits");
+                            self.close(id);
+                            return;
+                        }
+                        connection.ip = addr.ip();
+                        connection.limited = true;
+                    }
+
+                    let data = pending.header.split_off(offset);
+                    let legacy_ping = legacy_ping(&data);
+                    let queued = if legacy_ping.is_some() {
+                        connection.legacy = true;
+                        Ok(())
+                    } else {
+                        let max_packet_size = connection.decoder.max_packet_size();
+                        connection.decoder.lock().queue_slice(&data, max_packet_size)
+                    };
+                    announce(events, id, connection, addr, legacy_ping);
+                    if let Err(error) = queued {
+                        self.close(id);
+                        self.send(NetEvent::Closed { id, error });
+                        return;
+                    }
+                },
+                Err(err) => {
+                    tracing::warn!("Rejected connection from {}: {}", pending.addr, err);
+                    self.close(id);
+                    return;
+                },
+            }
+        }
+        if connection.legacy {
+            return;
+        }
+
+        match decode_packets(&connection.decoder) {
+            Ok(packets) if packets.is_empty() => {},
+            Ok(packets) => self.send(NetEvent::Packets { id, packets }),
+            Err(error) => {
+                self.close(id);
+                self.send(NetEvent::Closed { id, error });
+            },
+        }
+    }
+
+    /// Handles queued commands, returns false once the ECS is gone.
+    fn commands(&mut self) -> bool {
+        loop {
+            match self.commands.try_recv() {
+                Ok(IoCommand::Close(id)) => self.close(id),
+                Ok(IoCommand::LoggedIn(id)) => {
+                    let Some(connection) = self.connections.get_mut(&id) else { continue; };
+                    if !connection.logged_in {
+                        connection.logged_in = true;
+                        self.pre_login -= 1;
+                    }
+                },
+                Ok(IoCommand::Settings(settings)) => self.settings = settings,
+                Ok(IoCommand::StopAccepting) => {
+                    if let Some(mut listener) = self.listener.take() {
+                        let _ = self.poll.registry().deregister(&mut listener);
+                    }
+                },
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn close(&mut self, id: usize) {
+        if let Some(mut connection) = self.connections.remove(&id) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+            self.release(connection.ip, connection.limited, connection.logged_in);
+        }
+    }
+
+    fn send(&self, event: NetEvent) {
+        // the receiver only goes away while the server is shutting down
+        let _ = self.events.send(event);
+    }
+}
+
+/// Checks the per address limits and counts the connection against `ip` if
+/// it's let in.
+fn admit_ip(ips: &mut HashMap<IpAddr, IpState>, settings: &NetworkSettings, metrics: &NetworkMetrics, ip: IpAddr) -> bool {
+    let now = Instant::now();
+    let state = ips.entry(ip).or_insert_with(|| IpState {
+        connections: 0,
+        window_start: now,
+        window_connections: 0,
+    });
+    if now.duration_since(state.window_start) >= settings.throttle_window {
+        state.window_start = now;
+        state.window_connections = 0;
+    }
+
+    if settings.max_connections_per_ip != 0 && state.connections >= settings.max_connections_per_ip {
+        metrics.ip_limited.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    if settings.throttle_connections != 0 && state.window_connections >= settings.throttle_connections {
+        metrics.throttled.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    state.window_connections += 1;
+    state.connections += 1;
+    true
+}
+
+/// Hands the connection to the ECS with its real address.
+fn announce(events: &Sender<NetEvent>, id: usize, connection: &mut Connection, addr: SocketAddr, legacy_ping: Option<LegacyPing>) {
+    let Some(pending) = connection.pending.take() else { return; };
+    let _ = events.send(NetEvent::Accepted {
+        id,
+        stream: pending.write_half,
+        addr,
+        decoder: connection.decoder.clone(),
+        legacy_ping,
+        trusted: connection.trusted,
+    });
+}
+
+/// Waits for the PROXY header, if enabled, and at least one byte after it.
+/// Returns the client's address and where its data starts.
+fn first_bytes(pending: &mut PendingConnection, proxy_protocol: bool, trusted: bool) -> anyhow::Result<Option<(SocketAddr, usize)>> {
+    let (addr, offset) = match pending.proxied {
+        Some(proxied) => proxied,
+        None if !proxy_protocol => (pending.addr, 0),
+        None => match proxy_header(pending, trusted)? {
+            Some(proxied) => {
+                pending.proxied = Some(proxied);
+                proxied
+            },
+            None => return Ok(None),
+        },
+    };
+    Ok((pending.header.len() > offset).then_some((addr, offset)))
+}
+
+/// Pre-netty pings, told apart from the first read like vanilla's
+/// `LegacyQueryHandler`: `FE` alone before 1.4, `FE 01` up to 1.5 and
+/// `FE 01 FA` followed by the ping host in 1.6. Anything else, like a
+/// handshake frame of length 254, goes to the decoder.
+fn legacy_ping(data: &[u8]) -> Option<LegacyPing> {
+    match data {
+        [0xFE] => Some(LegacyPing { with_version: false }),
+        [0xFE, 0x01] | [0xFE, 0x01, 0xFA, ..] => Some(LegacyPing { with_version: true }),
+        _ => None,
     }
-    Ok(())
 }
 
-pub fn client_data(
-    stream: &mut ClientConnection,
-    dec: &mut PacketDecoder,
-    queue: &mut ClientPacketQueue
-) -> Result<(), PacketIoError> {
-    queue.clear();
+/// Checks the start of a pending connection for a PROXY header. Returns the
+/// client's address and the header length once that can be decided.
+fn proxy_header(pending: &PendingConnection, trusted: bool) -> anyhow::Result<Option<(SocketAddr, usize)>> {
+    match proxy::has_header(&pending.header) {
+        None => Ok(None),
+        Some(false) => {
+            anyhow::ensure!(!trusted, "trusted proxy didn't send a PROXY header");
+            Ok(Some((pending.addr, 0)))
+        },
+        Some(true) => {
+            anyhow::ensure!(trusted, "untrusted source sent a PROXY header");
+            Ok(proxy::parse(&pending.header)?.map(|(addr, len)| (addr.unwrap_or(pending.addr), len)))
+        },
+    }
+}
 
-    let guard = tracing::trace_span!("reading_data").entered();
+/// Reads what's available within the connection's budget, into the header
+/// buffer while the connection is pending and into the decoder afterwards.
+/// Returns false if the budget ran out before the socket was drained.
+fn read_connection(connection: &mut Connection) -> Result<bool, PacketIoError> {
+    let max_packet_size = connection.decoder.max_packet_size();
+    let mut dec = connection.decoder.lock();
+
+    let _guard = tracing::trace_span!("reading_data").entered();
     loop {
         let mut buf = [0u8; 4096];
-        let bytes_read = match stream.read(&mut buf) {
+        let len = match connection.budget {
+            Some(0) => return Ok(false),
+            Some(budget) => budget.min(buf.len()),
+            None => buf.len(),
+        };
+        let bytes_read = match (&connection.stream).read(&mut buf[..len]) {
             Ok(0) => return Err(PacketIoError::Disconnect),
             Ok(n) => n,
             Err(ref err) if would_block(err) => break,
             Err(ref err) if interrupted(err) => continue,
             Err(err) => return Err(err.into()),
         };
+        if let Some(budget) = &mut connection.budget {
+            *budget -= bytes_read;
+        }
 
-        dec.queue_slice(&buf[..bytes_read]);
+        match &mut connection.pending {
+            Some(pending) => pending.header.extend_from_slice(&buf[..bytes_read]),
+            None if connection.legacy => {},
+            None => dec.queue_slice(&buf[..bytes_read], max_packet_size)?,
+        }
     }
-    drop(guard);
+    Ok(true)
+}
+
+fn decode_packets(decoder: &PacketDecoder) -> Result<Vec<RawPacket>, PacketIoError> {
+    let max_packet_size = decoder.max_packet_size();
+    let mut dec = decoder.lock();
 
+    let mut packets = Vec::new();
     loop {
         let _guard = tracing::trace_span!("decoding_data").entered();
-        let packet = match dec.decode() {
+        let mut packet = match dec.decode() {
             Ok(packet) => match packet {
                 Some(packet) => packet,
                 None => break,
             },
             Err(err) => return Err(err.into()),
         };
+        if max_packet_size != 0 && packet.bytebuf.buf().len() > max_packet_size {
+            return Err(PacketIoError::BadPacket("packet too large"));
+        }
+
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+/// Drains events from the IO thread, spawning new clients and filling the
+/// packet queues of the ones that received data.
+/// Clients that sent more than `max_packets` since the last tick are disconnected.
+pub fn receive_events(world: &WorldRef, io: &mut NetworkIo, max_packets: usize) {
+    io.handle.next_tick();
+
+    // queues only hold the packets of a single tick
+    for entity in io.received.drain(..) {
+        let entity = world.entity_from_id(entity);
+        if entity.is_alive() {
+            entity.try_get::<&mut ClientPacketQueue>(|queue| queue.clear());
+        }
+    }
+
+    let mut accepted = HashMap::new();
+    let mut batches: HashMap<usize, Vec<RawPacket>> = HashMap::new();
+    for event in io.events.try_iter() {
+        match event {
+            NetEvent::Accepted { id, stream, addr, decoder, legacy_ping, trusted } => {
+                accepted.insert(id, (stream, addr, decoder, legacy_ping, trusted));
+            },
+            NetEvent::Packets { id, packets } => {
+                batches.entry(id).or_default().extend(packets);
+            },
+            NetEvent::Closed { id, error } => {
+                if !matches!(error, PacketIoError::Disconnect) {
+                    tracing::warn!("Client data handling failed: {}.", error);
+                }
+                batches.remove(&id);
+                if accepted.remove(&id).is_none() {
+                    if let Some(entity) = io.clients.get(&id) {
+                        world.entity_from_id(*entity).destruct();
+                    }
+                }
+            },
+        }
+    }
+
+    let mut flooded = Vec::new();
+    batches.retain(|id, packets| {
+        if max_packets != 0 && packets.len() > max_packets {
+            flooded.push((*id, packets.len()));
+            return false;
+        }
+        true
+    });
+
+    for (id, (stream, addr, decoder, legacy_ping, trusted)) in accepted {
+        let queue = batches.remove(&id).unwrap_or_default();
+        let client = world.entity()
+            .set(ClientConnection(stream))
+            .set(RemoteAddress(addr.ip()))
+            .set(PacketEncoder::default())
+            .set(decoder)
+            .set(ClientPacketQueue(queue))
+            .set(OutgoingBuffer::default())
+            .set(ConnectionId(id));
+        match legacy_ping {
+            Some(ping) => client.set(ping),
+            None => client.set(CurrentState::HandShake),
+        };
+        if trusted {
+            client.add::<TrustedProxy>();
+        }
+
+        io.clients.insert(id, client.id());
+        io.received.push(client.id());
+    }
+
+    for (id, packets) in batches {
+        let Some(entity) = io.clients.get(&id) else { continue; };
+        let entity = world.entity_from_id(*entity);
+        entity.try_get::<&mut ClientPacketQueue>(|queue| queue.extend(packets));
+        io.received.push(entity.id());
+    }
 
-        queue.push(packet);
+    for (id, count) in flooded {
+        let Some(entity) = io.clients.get(&id) else { continue; };
+        let entity = world.entity_from_id(*entity);
+        if !entity.has::<Disconnect>() {
+            tracing::warn!("Client {entity} sent {count} packets in one tick (limit {max_packets})");
+            entity.set(Disconnect::new("Sent too many packets"));
+        }
+    }
+}
+
+/// Appends the disconnect packet matching the connection's state. Handshake
+/// and status have none, play entities no longer carry a `CurrentState`.
+pub fn append_disconnect(enc: &mut PacketEncoder, state: Option<&CurrentState>, reason: &Text) -> Result<(), PacketError> {
+    match state {
+        Some(CurrentState::HandShake | CurrentState::Status | CurrentState::Transfer) => Ok(()),
+        Some(CurrentState::Login(_)) => {
+            let reason = serde_json::to_string(reason).expect("serialize text");
+            enc.append_packet(&CLoginDisconnect::new(&reason))
+        },
+        Some(CurrentState::Config(_)) => {
+            let reason = serde_json::to_string(reason).expect("serialize text");
+            enc.append_packet(&CConfigDisconnect::new(&reason))
+        },
+        Some(CurrentState::Play) | None => {
+            let reason = reason.to_legacy_lossy();
+            enc.append_packet(&CPlayDisconnect::new(&TextComponent::text(&reason)))
+        },
+    }
+}
+
+/// Writes as much of the outgoing buffer as the socket accepts without blocking.
+pub fn flush_outgoing(stream: &mut ClientConnection, outgoing: &mut OutgoingBuffer) -> io::Result<()> {
+    while !outgoing.is_empty() {
+        match stream.write(outgoing) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => outgoing.advance(n),
+            Err(ref err) if would_block(err) => break,
+            Err(ref err) if interrupted(err) => continue,
+            Err(err) => return Err(err),
+        }
     }
     Ok(())
 }
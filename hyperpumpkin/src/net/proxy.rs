@@ -0,0 +1,99 @@
+//! HAProxy PROXY protocol v1 and v2 headers.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, str};
+
+use anyhow::{bail, ensure, Context};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Larger than any address block, TLVs beyond this are not accepted.
+const V2_MAX_LEN: usize = 512;
+
+/// Whether `buf` starts with a PROXY header, `None` until enough bytes arrived to tell.
+pub fn has_header(buf: &[u8]) -> Option<bool> {
+    for signature in [V1_PREFIX, V2_SIGNATURE] {
+        let len = buf.len().min(signature.len());
+        if buf[..len] == signature[..len] {
+            return if len == signature.len() { Some(true) } else { None };
+        }
+    }
+    Some(false)
+}
+
+/// Parses a complete header at the start of `buf`.
+/// Returns `None` if more bytes are needed, otherwise the source address (if the
+/// proxy sent one) and the header length.
+pub fn parse(buf: &[u8]) -> anyhow::Result<Option<(Option<SocketAddr>, usize)>> {
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else {
+        bail!("not a proxy protocol header")
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> anyhow::Result<Option<(Option<SocketAddr>, usize)>> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        ensure!(buf.len() < V1_MAX_LEN, "proxy v1 header too long");
+        return Ok(None);
+    };
+    let len = end + 2;
+    ensure!(len <= V1_MAX_LEN, "proxy v1 header too long");
+
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end]).context("proxy v1 header is not utf-8")?;
+    let mut fields = line.split(' ');
+    let addr = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some("TCP4" | "TCP6") => {
+            let ip: IpAddr = fields.next().context("missing source address")?.parse()?;
+            let _destination = fields.next().context("missing destination address")?;
+            let port: u16 = fields.next().context("missing source port")?.parse()?;
+            Some(SocketAddr::new(ip, port))
+        },
+        _ => bail!("unknown proxy v1 protocol"),
+    };
+    Ok(Some((addr, len)))
+}
+
+fn parse_v2(buf: &[u8]) -> anyhow::Result<Option<(Option<SocketAddr>, usize)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let body_len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let len = V2_HEADER_LEN + body_len;
+
+    ensure!(version_command >> 4 == 2, "unsupported proxy protocol version");
+    ensure!(len <= V2_MAX_LEN, "proxy v2 header too long");
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let body = &buf[V2_HEADER_LEN..len];
+
+    let addr = match (version_command & 0x0F, family) {
+        // LOCAL: health checks from the proxy itself
+        (0x0, _) => None,
+        // PROXY over TCP4
+        (0x1, 0x11) => {
+            ensure!(body.len() >= 12, "proxy v2 address block too short");
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        },
+        // PROXY over TCP6
+        (0x1, 0x21) => {
+            ensure!(body.len() >= 36, "proxy v2 address block too short");
+            let octets: [u8; 16] = body[..16].try_into().expect("slice is 16 bytes");
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        },
+        (0x1, _) => None,
+        _ => bail!("unknown proxy v2 command"),
+    };
+    Ok(Some((addr, len)))
+}